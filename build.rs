@@ -1,4 +1,101 @@
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// The programs packed into the ramdisk, by path in the archive, and the
+/// variable cargo passes the path of their artifact in.
+const PROGRAMS: &[(&str, &str)] = &[
+    ("bin/program", "CARGO_BIN_FILE_PROGRAM_program"),
+    ("bin/program2", "CARGO_BIN_FILE_PROGRAM2_program2"),
+    ("bin/forktest", "CARGO_BIN_FILE_FORKTEST_forktest"),
+];
+
+/// The programs that use `std`, by path in the archive, and their package in
+/// `user`.
+const STD_PROGRAMS: &[(&str, &str)] = &[("bin/hello", "hello")];
+
+/// The program the kernel starts at boot.
+const INIT: &str = "/bin/program";
+
+/// Appends a regular file to a ustar archive.
+fn append_file(archive: &mut Vec<u8>, path: &str, contents: &[u8]) {
+    let mut header = [0u8; 512];
+    assert!(path.len() < 100, "{path} is too long for a ustar header");
+
+    header[..path.len()].copy_from_slice(path.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", contents.len()).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces.
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(contents);
+    archive.resize(archive.len().next_multiple_of(512), 0);
+}
+
+/// Builds a program that uses `std` for the `x86_64-sharkos` target and
+/// returns the path of its executable. These need `-Zbuild-std` and a target
+/// spec file, which artifact dependencies do not support, so they are built
+/// by a nested cargo into `out_dir`.
+fn build_std_program(out_dir: &Path, package: &str) -> PathBuf {
+    let user = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("user");
+    let target_dir = out_dir.join("user");
+
+    println!("cargo:rerun-if-changed={}", user.display());
+
+    let status = Command::new(std::env::var_os("CARGO").unwrap())
+        .current_dir(user.join(package))
+        .args(["build", "--release", "-Zbuild-std=std,panic_abort"])
+        .arg("--target")
+        .arg(user.join("x86_64-sharkos.json"))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        // The flags of the outer build are for the host.
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .status()
+        .unwrap();
+    assert!(status.success(), "Failed to build {package}");
+
+    target_dir.join("x86_64-sharkos/release").join(package)
+}
+
+/// Packs the user programs into a ustar archive for the kernel to load them
+/// from, along with `/etc/init` naming the first one to run.
+fn create_ramdisk(path: &Path, out_dir: &Path) {
+    let mut archive = Vec::new();
+
+    let programs = PROGRAMS
+        .iter()
+        .map(|&(name, variable)| (name, PathBuf::from(std::env::var_os(variable).unwrap())))
+        .chain(
+            STD_PROGRAMS
+                .iter()
+                .map(|&(name, package)| (name, build_std_program(out_dir, package))),
+        );
+
+    for (name, file) in programs {
+        let contents =
+            std::fs::read(&file).unwrap_or_else(|error| panic!("{}: {error}", file.display()));
+        append_file(&mut archive, name, &contents);
+    }
+
+    append_file(&mut archive, "etc/init", format!("{INIT}\n").as_bytes());
+
+    // The archive ends with two zeroed blocks.
+    archive.resize(archive.len() + 1024, 0);
+
+    std::fs::write(path, archive).unwrap();
+}
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -8,15 +105,22 @@ fn main() {
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
 
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let ramdisk_path = out_dir.join("ramdisk.tar");
+    create_ramdisk(&ramdisk_path, &out_dir);
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
         .create_disk_image(&uefi_path)
         .unwrap();
 
     // create a BIOS disk image
     let bios_path = out_dir.join("bios.img");
     bootloader::BiosBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
         .create_disk_image(&bios_path)
         .unwrap();
 
@@ -0,0 +1,697 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use x86_64::instructions::tlb;
+
+use crate::{
+    allocator::{allocate_aligned, allocate_page, free_page, references, share_page, Owner},
+    paging::{self, Flags},
+    random, shared_memory, PHYSICAL_OFFSET, USER_END,
+};
+
+/// Where anonymous mappings are placed when no address is asked for. Each
+/// address space starts at a random page in this range.
+const MMAP_AREA: Range<usize> = 0x0000_1000_0000_0000..0x0000_2000_0000_0000;
+
+/// Where position-independent programs are loaded, at a random 2 MiB
+/// boundary so that huge pages can still be used.
+pub const PROGRAM_AREA: Range<usize> = 0x0000_5000_0000_0000..0x0000_6000_0000_0000;
+
+/// Where the main stack is placed, at a random page.
+pub const STACK_AREA: Range<usize> = 0x0000_7000_0000_0000..0x0000_7f00_0000_0000;
+
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// Translates `PROT_*` bits into page flags, or returns `None` if unknown
+/// bits are set. Write or execute access implies read access, since the
+/// hardware cannot express anything else.
+pub fn protection_flags(protection: u64) -> Option<Flags> {
+    if protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return None;
+    }
+
+    if protection == 0 {
+        return Some(Flags::NONE);
+    }
+
+    let mut flags = Flags::USER;
+    if protection & PROT_WRITE != 0 {
+        flags = flags | Flags::WRITE;
+    }
+    if protection & PROT_EXEC == 0 {
+        flags = flags | Flags::NOT_EXECUTABLE;
+    }
+
+    Some(flags)
+}
+
+/// What a region of a process's address space is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Code,
+    Data,
+    Stack,
+    Heap,
+    Mmap,
+    /// A mapping of the shared memory object with the given id.
+    Shared(usize),
+    /// Never mapped, so that running off the end of the stack below it
+    /// faults instead of corrupting other memory.
+    Guard,
+}
+
+/// Why a page fault in user memory could not be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The address is not inside any region.
+    Unmapped,
+    /// The access is not allowed by the region's flags.
+    AccessViolation,
+    /// The address is in the guard page below a stack.
+    StackOverflow,
+    OutOfMemory,
+}
+
+impl core::fmt::Display for FaultError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            FaultError::Unmapped => "access to unmapped memory",
+            FaultError::AccessViolation => "access violation",
+            FaultError::StackOverflow => "stack overflow",
+            FaultError::OutOfMemory => "out of memory",
+        })
+    }
+}
+
+/// A page-aligned range of user memory and the flags it is mapped with.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub kind: RegionKind,
+    pub flags: Flags,
+}
+
+impl Region {
+    pub fn contains(&self, address: usize) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// The user half of a process's page tables and the regions mapped in it.
+/// Every frame and table in the user half is owned by the address space and
+/// is released when it is dropped.
+#[derive(Debug)]
+pub struct AddressSpace {
+    table: *mut paging::Table,
+    regions: Vec<Region>,
+    /// Where the search for room for anonymous mappings starts.
+    mmap_base: usize,
+}
+
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
+    /// Creates an address space sharing the kernel half of the current page
+    /// table and with nothing mapped in the user half. Returns `None` if
+    /// there is no memory left for its table.
+    pub fn new() -> Option<Self> {
+        Some(AddressSpace {
+            table: paging::Table::new_copy()?,
+            regions: Vec::new(),
+            mmap_base: random::random_address(MMAP_AREA.start, MMAP_AREA.end, 4096),
+        })
+    }
+
+    pub fn table(&mut self) -> &mut paging::Table {
+        unsafe { &mut *self.table }
+    }
+
+    /// The physical address of the level-4 table, as loaded into CR3.
+    pub fn cr3(&self) -> u64 {
+        self.table as u64 - unsafe { PHYSICAL_OFFSET }
+    }
+
+    pub fn find_region(&self, address: usize) -> Option<&Region> {
+        self.regions.iter().find(|region| region.contains(address))
+    }
+
+    /// Records a region whose pages have been mapped by the caller, or are
+    /// backed on demand when first accessed.
+    pub fn add_region(&mut self, start: usize, end: usize, kind: RegionKind, flags: Flags) {
+        assert!(
+            start & 0xfff == 0 && end & 0xfff == 0,
+            "Regions must be page-aligned"
+        );
+
+        assert!(
+            !self
+                .regions
+                .iter()
+                .any(|region| region.overlaps(start, end)),
+            "Region {:#x}..{:#x} overlaps an existing region",
+            start,
+            end
+        );
+
+        self.regions.push(Region {
+            start,
+            end,
+            kind,
+            flags,
+        });
+    }
+
+    /// Reserves a non-executable stack of `size` bytes above a guard page at
+    /// `start`, with pages backed as the stack grows into them. Returns the
+    /// top of the stack.
+    pub fn reserve_stack(&mut self, start: usize, size: usize) -> usize {
+        let top = start + 4096 + size;
+
+        self.add_region(start, start + 4096, RegionKind::Guard, Flags::NONE);
+        self.add_region(
+            start + 4096,
+            top,
+            RegionKind::Stack,
+            Flags::ALL | Flags::NOT_EXECUTABLE,
+        );
+
+        top
+    }
+
+    /// Resolves a page fault at `address`, either by backing a reserved page
+    /// with a zeroed frame or by copying a copy-on-write page.
+    pub fn handle_page_fault(
+        &mut self,
+        address: usize,
+        present: bool,
+        write: bool,
+    ) -> Result<(), FaultError> {
+        let region = *self.find_region(address).ok_or(FaultError::Unmapped)?;
+
+        if region.kind == RegionKind::Guard {
+            return Err(FaultError::StackOverflow);
+        }
+
+        if !region.flags.contains(Flags::USER) {
+            return Err(FaultError::AccessViolation);
+        }
+
+        if present {
+            let writable = region.flags.contains(Flags::WRITE);
+
+            return match write && writable && self.resolve_copy_on_write(address) {
+                true => Ok(()),
+                false => Err(FaultError::AccessViolation),
+            };
+        }
+
+        if write && !region.flags.contains(Flags::WRITE) {
+            return Err(FaultError::AccessViolation);
+        }
+
+        let page = allocate_page(1, Owner::User);
+        if page == 0 {
+            return Err(FaultError::OutOfMemory);
+        }
+
+        unsafe {
+            if !self
+                .table()
+                .create_mapping(address & !0xfff, page, region.flags)
+            {
+                free_page(page as u64, 1);
+                return Err(FaultError::OutOfMemory);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_free(&self, start: usize, end: usize) -> bool {
+        !self
+            .regions
+            .iter()
+            .any(|region| region.overlaps(start, end))
+    }
+
+    /// Moves the end of the heap starting at `start` from `old_end` to
+    /// `new_end`, all page-aligned. Pages past the new end are freed, while
+    /// new pages are backed on demand. Returns false if the heap would run
+    /// into another region.
+    pub fn resize_heap(&mut self, start: usize, old_end: usize, new_end: usize) -> bool {
+        if new_end <= old_end {
+            return new_end == old_end || self.unmap(new_end, old_end - new_end);
+        }
+
+        if new_end > USER_END || !self.is_free(old_end, new_end) {
+            return false;
+        }
+
+        let heap = self.regions.iter_mut().find(|region| {
+            region.kind == RegionKind::Heap && region.start == start && region.end == old_end
+        });
+
+        match heap {
+            Some(heap) => heap.end = new_end,
+            None => self.add_region(
+                old_end,
+                new_end,
+                RegionKind::Heap,
+                Flags::ALL | Flags::NOT_EXECUTABLE,
+            ),
+        }
+
+        true
+    }
+
+    /// Adds an anonymous region of `length` bytes, backed on demand. It is
+    /// placed at `address` if that is free, and anywhere above the mmap base
+    /// otherwise. Returns the start of the region.
+    pub fn map_anonymous(&mut self, address: usize, length: usize, flags: Flags) -> Option<usize> {
+        let length = length.checked_add(0xfff)? & !0xfff;
+        let start = self.find_free(address, length)?;
+
+        self.add_region(start, start + length, RegionKind::Mmap, flags);
+        Some(start)
+    }
+
+    /// Maps shared memory object `id` into this address space for `pid`,
+    /// anywhere above the mmap base. Returns the start of the region, or
+    /// `None` if `pid` may not map the object or no space is left.
+    pub fn map_shared(&mut self, id: usize, pid: usize, flags: Flags) -> Option<usize> {
+        let frames = shared_memory::map(id, pid)?;
+
+        let Some(start) = self.find_free(0, frames.len() * 4096) else {
+            shared_memory::remove_mapping(id);
+            return None;
+        };
+
+        self.add_region(
+            start,
+            start + frames.len() * 4096,
+            RegionKind::Shared(id),
+            flags,
+        );
+
+        for (index, &frame) in frames.iter().enumerate() {
+            let mapped = unsafe {
+                self.table()
+                    .create_mapping(start + index * 4096, frame, flags)
+            };
+
+            // Unmapping drops the region, the mapping and the frames mapped
+            // so far.
+            if !mapped {
+                self.unmap(start, frames.len() * 4096);
+                return None;
+            }
+
+            share_page(frame as u64, 1);
+        }
+
+        Some(start)
+    }
+
+    /// Finds room for `length` bytes, which must be a multiple of the page
+    /// size. Returns `address` if that range is free, and the first free
+    /// range above the mmap base otherwise.
+    fn find_free(&self, address: usize, length: usize) -> Option<usize> {
+        if length == 0 {
+            return None;
+        }
+
+        let start = address & !0xfff;
+        if start != 0
+            && start.checked_add(length)? <= USER_END
+            && self.is_free(start, start + length)
+        {
+            return Some(start);
+        }
+
+        let mut start = self.mmap_base;
+        loop {
+            let end = start.checked_add(length).filter(|&end| end <= USER_END)?;
+
+            match self
+                .regions
+                .iter()
+                .find(|region| region.overlaps(start, end))
+            {
+                Some(region) => start = region.end,
+                None => return Some(start),
+            }
+        }
+    }
+
+    /// Splits the region containing `address`, if any, so that no region
+    /// crosses it.
+    fn split_region(&mut self, address: usize) {
+        let Some(index) = self
+            .regions
+            .iter()
+            .position(|region| region.start < address && address < region.end)
+        else {
+            return;
+        };
+
+        let upper = Region {
+            start: address,
+            ..self.regions[index]
+        };
+
+        if let RegionKind::Shared(id) = upper.kind {
+            shared_memory::add_mapping(id);
+        }
+
+        self.regions[index].end = address;
+        self.regions.push(upper);
+    }
+
+    /// Returns the end of the range of `length` bytes from `start`, rounded
+    /// up to a page, if it is a range of user memory whose ends do not fall
+    /// inside a huge page.
+    fn range_end(&mut self, start: usize, length: usize) -> Option<usize> {
+        let end = start.checked_add(length)?.checked_next_multiple_of(4096)?;
+
+        let inside_huge_page = |table: &mut paging::Table, address: usize| {
+            table
+                .entry(address)
+                .is_some_and(|(_, size)| address & (size.bytes() - 1) != 0)
+        };
+
+        let valid = start & 0xfff == 0
+            && start < end
+            && end <= USER_END
+            && !inside_huge_page(self.table(), start)
+            && !inside_huge_page(self.table(), end);
+
+        valid.then_some(end)
+    }
+
+    /// Removes every region, or part of one, inside the `length` bytes from
+    /// `start` and frees the pages mapped there. Returns false if the range
+    /// is invalid.
+    pub fn unmap(&mut self, start: usize, length: usize) -> bool {
+        let Some(end) = self.range_end(start, length) else {
+            return false;
+        };
+
+        self.split_region(start);
+        self.split_region(end);
+        self.regions.retain(|region| {
+            let inside = start <= region.start && region.end <= end;
+
+            if let (true, RegionKind::Shared(id)) = (inside, region.kind) {
+                shared_memory::remove_mapping(id);
+            }
+
+            !inside
+        });
+
+        unsafe {
+            self.table()
+                .unmap_range(start, (end - start) / 4096, |address, size| {
+                    free_page(address as u64, size.pages());
+                });
+        }
+
+        true
+    }
+
+    /// Changes the flags of every page in the `length` bytes from `start`.
+    /// Returns false if the range is invalid or not entirely covered by
+    /// regions.
+    pub fn protect(&mut self, start: usize, length: usize, flags: Flags) -> bool {
+        let Some(end) = self.range_end(start, length) else {
+            return false;
+        };
+
+        let covered: usize = self
+            .regions
+            .iter()
+            .filter(|region| region.overlaps(start, end))
+            .map(|region| region.end.min(end) - region.start.max(start))
+            .sum();
+
+        if covered != end - start {
+            return false;
+        }
+
+        self.split_region(start);
+        self.split_region(end);
+        for region in self.regions.iter_mut() {
+            if start <= region.start && region.end <= end {
+                region.flags = flags;
+            }
+        }
+
+        let mut address = start;
+        while address < end {
+            let Some((entry, size)) = self.table().entry(address) else {
+                address += self.table().unmapped_span(address).unwrap();
+                continue;
+            };
+
+            // Pages still shared with another process stay read-only until
+            // they are copied, unless they are meant to be shared.
+            let in_shared_memory = self
+                .find_region(address)
+                .is_some_and(|region| matches!(region.kind, RegionKind::Shared(_)));
+            let shared = !in_shared_memory
+                && (entry.flags().contains(Flags::COPY_ON_WRITE)
+                    || references(entry.address()) > 1);
+            let flags = match shared && flags.contains(Flags::WRITE) {
+                true => (flags & !Flags::WRITE) | Flags::COPY_ON_WRITE,
+                false => flags,
+            };
+
+            unsafe {
+                self.table().update_flags(address, flags);
+            }
+
+            address += size.bytes();
+        }
+
+        true
+    }
+
+    /// Copies `bytes` to `address` through the physical memory window, so
+    /// that this need not be the active address space. Pages are backed or
+    /// copied as if written to by the process. Returns false if part of the
+    /// range is not writable.
+    pub fn write(&mut self, address: usize, bytes: &[u8]) -> bool {
+        if address
+            .checked_add(bytes.len())
+            .is_none_or(|end| end > USER_END)
+        {
+            return false;
+        }
+
+        let mut written = 0;
+
+        while written < bytes.len() {
+            let current = address + written;
+
+            let writable = self
+                .find_region(current)
+                .is_some_and(|region| region.flags.contains(Flags::USER | Flags::WRITE));
+            if !writable {
+                return false;
+            }
+
+            let present = match self.table().entry(current) {
+                Some((entry, _)) if !entry.flags().contains(Flags::COPY_ON_WRITE) => true,
+                Some(_) => self.handle_page_fault(current, true, true).is_ok(),
+                None => self.handle_page_fault(current, false, true).is_ok(),
+            };
+            if !present || !self.user_page(current, Flags::WRITE) {
+                return false;
+            }
+
+            let physical_address = self.table().translate(current).unwrap();
+            let length = (4096 - current % 4096).min(bytes.len() - written);
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    bytes[written..].as_ptr(),
+                    (physical_address as u64 + PHYSICAL_OFFSET) as *mut u8,
+                    length,
+                );
+            }
+
+            written += length;
+        }
+
+        true
+    }
+
+    /// Copies from `address` into `buffer` through the physical memory
+    /// window, backing pages as if read by the process. Returns false if part
+    /// of the range is not readable.
+    pub fn read(&mut self, address: usize, buffer: &mut [u8]) -> bool {
+        if address
+            .checked_add(buffer.len())
+            .is_none_or(|end| end > USER_END)
+        {
+            return false;
+        }
+
+        let mut read = 0;
+
+        while read < buffer.len() {
+            let current = address + read;
+
+            let present = self.table().entry(current).is_some()
+                || self.handle_page_fault(current, false, false).is_ok();
+            if !present || !self.user_page(current, Flags::NONE) {
+                return false;
+            }
+
+            let physical_address = self.table().translate(current).unwrap();
+            let length = (4096 - current % 4096).min(buffer.len() - read);
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    (physical_address as u64 + PHYSICAL_OFFSET) as *const u8,
+                    buffer[read..].as_mut_ptr(),
+                    length,
+                );
+            }
+
+            read += length;
+        }
+
+        true
+    }
+
+    /// Checks that the page holding `address` is mapped for user space with
+    /// `flags`.
+    fn user_page(&mut self, address: usize, flags: Flags) -> bool {
+        self.table()
+            .entry(address)
+            .is_some_and(|(entry, _)| entry.flags().contains(Flags::USER | flags))
+    }
+
+    /// Creates a copy of this address space for a forked process. Both sides
+    /// share every page, with writable ones outside shared memory turned
+    /// read-only and copied on the first write. Returns `None` if there is
+    /// no memory left for the child's tables.
+    pub fn fork(&mut self) -> Option<AddressSpace> {
+        let mut child = AddressSpace::new()?;
+        child.regions = self.regions.clone();
+        child.mmap_base = self.mmap_base;
+
+        for region in &self.regions {
+            if let RegionKind::Shared(id) = region.kind {
+                shared_memory::add_mapping(id);
+            }
+        }
+
+        let table = unsafe { &mut *self.table };
+        let regions = &self.regions;
+        let mut complete = true;
+
+        table.for_each_user_page(|address, entry, size| {
+            if !complete {
+                return;
+            }
+
+            let kind = regions
+                .iter()
+                .find(|region| region.contains(address))
+                .map(|region| region.kind);
+
+            // Shared memory stays shared with the child.
+            let shared = matches!(kind, Some(RegionKind::Shared(_)));
+
+            if entry.flags().contains(Flags::WRITE) && !shared {
+                entry.replace_flags((entry.flags() & !Flags::WRITE) | Flags::COPY_ON_WRITE);
+            }
+
+            complete = unsafe {
+                child.table().create_sized_mapping(
+                    address,
+                    entry.address() as usize,
+                    size,
+                    entry.flags(),
+                )
+            };
+
+            if complete {
+                share_page(entry.address(), size.pages());
+            }
+        });
+
+        // Writable entries of the running process were just made read-only.
+        tlb::flush_all();
+
+        // Dropping a partial child releases what it did map.
+        complete.then_some(child)
+    }
+
+    /// Handles a write to a copy-on-write page by giving this address space
+    /// its own writable copy. Returns false if `address` is not in such a
+    /// page.
+    pub fn resolve_copy_on_write(&mut self, address: usize) -> bool {
+        let Some((entry, size)) = self.table().entry(address) else {
+            return false;
+        };
+
+        if !entry.flags().contains(Flags::COPY_ON_WRITE) {
+            return false;
+        }
+
+        let page = address & !(size.bytes() - 1);
+        let flags = (entry.flags() & !Flags::COPY_ON_WRITE) | Flags::WRITE;
+
+        unsafe {
+            // Nobody else holds the page anymore, so it can be written to.
+            if references(entry.address()) == 1 {
+                return self.table().update_flags(page, flags);
+            }
+
+            let copy = allocate_aligned(size.pages(), size.pages(), Owner::User);
+            if copy == 0 {
+                return false;
+            }
+
+            core::ptr::copy_nonoverlapping(
+                (entry.address() + PHYSICAL_OFFSET) as *const u8,
+                (copy as u64 + PHYSICAL_OFFSET) as *mut u8,
+                size.bytes(),
+            );
+
+            self.table().replace_mapping(page, copy, flags);
+            free_page(entry.address(), size.pages());
+        }
+
+        true
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees every user frame and table page. The kernel table must be
+    /// active, since the TLB is not flushed.
+    fn drop(&mut self) {
+        unsafe {
+            self.table().clear_user_half(|address, size| {
+                free_page(address as u64, size.pages());
+            });
+
+            free_page(self.cr3(), 1);
+        }
+
+        for region in &self.regions {
+            if let RegionKind::Shared(id) = region.kind {
+                shared_memory::remove_mapping(id);
+            }
+        }
+    }
+}
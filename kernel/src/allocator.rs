@@ -3,95 +3,407 @@ use spinning_top::Spinlock;
 
 use crate::PHYSICAL_OFFSET;
 
-// TODO: Get a better allocator
-static mut BLOCK_HEAD: Spinlock<*mut UnallocatedPage> =
-    Spinlock::new(10000 as *mut UnallocatedPage);
+pub const PAGE_SIZE: u64 = 4096;
 
-struct UnallocatedPage {
-    /// The pointer to the start of the next free block.
-    next: *mut UnallocatedPage,
-    /// The number of free pages after and including this block.
-    size: u64,
+/// The largest block the allocator tracks is `2^MAX_ORDER` pages (1 GiB).
+pub const MAX_ORDER: usize = 18;
+
+static FRAMES: Spinlock<BuddyAllocator> = Spinlock::new(BuddyAllocator::new());
+
+/// The subsystem a frame was handed out to, used for accounting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Owner {
+    KernelHeap,
+    PageTable,
+    User,
 }
 
-pub fn initialize(memory_regions: &MemoryRegions) {
-    let mut regions = memory_regions
-        .iter()
-        .filter(|region| region.kind == MemoryRegionKind::Usable)
-        .peekable();
+impl Owner {
+    const COUNT: usize = 3;
+}
+
+/// A snapshot of physical memory usage, in pages. Shared with user space by
+/// the `meminfo` system call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryStatistics {
+    /// Every page described by the bootloader's memory map.
+    pub total: u64,
+    /// Pages currently on the allocator's free lists.
+    pub free: u64,
+    /// Pages that can never be allocated: firmware, the kernel image,
+    /// bootloader data and the allocator's own frame table.
+    pub reserved: u64,
+    pub kernel_heap: u64,
+    pub page_tables: u64,
+    pub user: u64,
+}
 
-    let mut lock = unsafe { BLOCK_HEAD.lock() };
+impl core::fmt::Display for MemoryStatistics {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let kib = |pages: u64| pages * PAGE_SIZE / 1024;
 
-    unsafe {
-        *lock = (regions.peek().unwrap().start + PHYSICAL_OFFSET) as *mut UnallocatedPage;
+        write!(
+            f,
+            "Memory: {} KiB total, {} KiB free, {} KiB reserved\n\
+             Used: {} KiB kernel heap, {} KiB page tables, {} KiB user",
+            kib(self.total),
+            kib(self.free),
+            kib(self.reserved),
+            kib(self.kernel_heap),
+            kib(self.page_tables),
+            kib(self.user)
+        )
+    }
+}
+
+/// Header written into the first page of every free block.
+struct FreeBlock {
+    next: *mut FreeBlock,
+    previous: *mut FreeBlock,
+}
+
+/// Bookkeeping kept for every physical frame below the highest usable address.
+#[derive(Clone, Copy)]
+struct Frame {
+    /// True if this frame is the first frame of a block on a free list.
+    free: bool,
+    /// The order of the free block starting at this frame, only meaningful
+    /// when `free` is set.
+    order: u8,
+    /// Who the frame was last allocated to.
+    owner: Owner,
+    /// The number of holders of an allocated frame, such as page tables
+    /// sharing it after a fork. The frame is freed when this reaches zero.
+    /// Each reference is a page table entry, so overflowing this would take
+    /// 32 GiB of page tables mapping the one frame.
+    references: u32,
+}
+
+impl Frame {
+    const RESERVED: Self = Frame {
+        free: false,
+        order: 0,
+        owner: Owner::KernelHeap,
+        references: 0,
+    };
+}
+
+/// A binary buddy allocator over physical frames. Free blocks of `2^order`
+/// pages are kept on one doubly linked list per order and are always
+/// naturally aligned to their size, so a block's buddy can be found by
+/// flipping a single bit of its frame number.
+struct BuddyAllocator {
+    free_lists: [*mut FreeBlock; MAX_ORDER + 1],
+    frames: *mut Frame,
+    frame_count: usize,
+    total: u64,
+    reserved: u64,
+    free: u64,
+    used: [u64; Owner::COUNT],
+}
+
+unsafe impl Send for BuddyAllocator {}
+
+impl BuddyAllocator {
+    const fn new() -> Self {
+        BuddyAllocator {
+            free_lists: [core::ptr::null_mut(); MAX_ORDER + 1],
+            frames: core::ptr::null_mut(),
+            frame_count: 0,
+            total: 0,
+            reserved: 0,
+            free: 0,
+            used: [0; Owner::COUNT],
+        }
+    }
+
+    fn frame(&mut self, index: usize) -> &mut Frame {
+        assert!(index < self.frame_count, "Frame {:#x} is untracked", index);
+        unsafe { &mut *self.frames.add(index) }
+    }
+
+    fn block(index: usize) -> *mut FreeBlock {
+        (index as u64 * PAGE_SIZE + unsafe { PHYSICAL_OFFSET }) as *mut FreeBlock
+    }
+
+    fn index(block: *mut FreeBlock) -> usize {
+        ((block as u64 - unsafe { PHYSICAL_OFFSET }) / PAGE_SIZE) as usize
     }
 
-    while let Some(region) = regions.next() {
+    fn push(&mut self, index: usize, order: usize) {
+        let block = Self::block(index);
+        let head = self.free_lists[order];
+
         unsafe {
-            *((region.start + PHYSICAL_OFFSET) as *mut UnallocatedPage) = UnallocatedPage {
-                next: (regions
-                    .peek()
-                    .map(|region| region.start + PHYSICAL_OFFSET)
-                    .unwrap_or(u64::MAX)) as *mut UnallocatedPage,
-                size: (region.end - region.start) / 4096,
+            (*block).next = head;
+            (*block).previous = core::ptr::null_mut();
+
+            if !head.is_null() {
+                (*head).previous = block;
             }
         }
+
+        self.free_lists[order] = block;
+
+        let frame = self.frame(index);
+        frame.free = true;
+        frame.order = order as u8;
     }
-}
 
-/// Allocates a number of free pages given by amount and zeros them out.
-/// Returns the start of the physical address.
-pub fn allocate_page(amount: u64) -> usize {
-    let mut lock = unsafe { BLOCK_HEAD.lock() };
-    let mut cursor = *lock;
-    let mut trailing = None;
+    fn remove(&mut self, index: usize, order: usize) {
+        let block = Self::block(index);
 
-    unsafe {
-        while (*cursor).size < amount {
-            if (*cursor).next as u64 == u64::MAX {
-                return 0; // No sufficient space was found
+        unsafe {
+            if (*block).previous.is_null() {
+                self.free_lists[order] = (*block).next;
+            } else {
+                (*(*block).previous).next = (*block).next;
+            }
+
+            if !(*block).next.is_null() {
+                (*(*block).next).previous = (*block).previous;
             }
+        }
+
+        self.frame(index).free = false;
+    }
+
+    /// Takes a block of exactly `2^order` pages, splitting a larger block if
+    /// none of that size are free.
+    fn allocate(&mut self, order: usize) -> Option<usize> {
+        let mut current = (order..=MAX_ORDER).find(|&order| !self.free_lists[order].is_null())?;
+        let index = Self::index(self.free_lists[current]);
+
+        self.remove(index, current);
 
-            trailing = Some(cursor);
-            cursor = (*cursor).next;
+        while current > order {
+            current -= 1;
+            self.push(index + (1 << current), current);
         }
 
-        let alloc_start_addr = cursor as u64 + (*cursor).size * 4096 - amount * 4096;
-        (*cursor).size -= amount;
-        if (*cursor).size == 0 {
-            // Remove the empty block from the list
-            match trailing {
-                None => *lock = (*cursor).next,
-                Some(previous) => (*previous).next = (*cursor).next,
+        Some(index)
+    }
+
+    /// Returns a block of `2^order` pages, merging it with its buddy for as
+    /// long as the buddy is also free.
+    fn free(&mut self, mut index: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+
+            if buddy >= self.frame_count {
+                break;
             }
+
+            let frame = *self.frame(buddy);
+            if !frame.free || frame.order as usize != order {
+                break;
+            }
+
+            self.remove(buddy, order);
+            index = index.min(buddy);
+            order += 1;
         }
 
-        // Zero out the allocated memory
-        core::ptr::write_bytes(alloc_start_addr as *mut u8, 0, (amount * 4096) as usize);
+        self.push(index, order);
+    }
+
+    /// Frees an arbitrary run of pages by splitting it into the largest
+    /// naturally aligned blocks that fit.
+    fn free_range(&mut self, mut index: usize, mut count: usize) {
+        while count > 0 {
+            let order = (index.trailing_zeros() as usize)
+                .min(count.ilog2() as usize)
+                .min(MAX_ORDER);
 
-        // Return the starting physical address of the allocated memory
-        (alloc_start_addr - PHYSICAL_OFFSET) as usize
+            self.free(index, order);
+            index += 1 << order;
+            count -= 1 << order;
+        }
     }
 }
 
-pub unsafe fn _free_page(physical_address: u64, amount: u64) {
-    let mut lock = unsafe { BLOCK_HEAD.lock() };
-    let mut cursor = *lock;
-    let mut trailing = None;
+/// The smallest order whose block holds at least `pages` pages.
+fn order_for(pages: u64) -> usize {
+    pages.next_power_of_two().trailing_zeros() as usize
+}
 
-    unsafe {
-        while ((*cursor).next as u64) < physical_address + PHYSICAL_OFFSET {
-            cursor = (*cursor).next;
-            trailing = Some(cursor);
+pub fn initialize(memory_regions: &MemoryRegions) {
+    let usable = || {
+        memory_regions
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            // Frame zero is skipped so that a zero address can signal failure.
+            .map(|region| {
+                (
+                    region.start.max(PAGE_SIZE).div_ceil(PAGE_SIZE) as usize,
+                    (region.end / PAGE_SIZE) as usize,
+                )
+            })
+            .filter(|(start, end)| start < end)
+    };
+
+    let frame_count = usable().map(|(_, end)| end).max().unwrap();
+    let table_pages = (frame_count * core::mem::size_of::<Frame>()).div_ceil(PAGE_SIZE as usize);
+
+    // Store the frame table at the start of the first region large enough.
+    let (table_start, _) = usable()
+        .find(|(start, end)| end - start >= table_pages)
+        .expect("No usable region can hold the frame table");
+    let table_end = table_start + table_pages;
+
+    let mut allocator = FRAMES.lock();
+    allocator.frames = BuddyAllocator::block(table_start) as *mut Frame;
+    allocator.frame_count = frame_count;
+    allocator.total = memory_regions
+        .iter()
+        .map(|region| (region.end - region.start) / PAGE_SIZE)
+        .sum();
+
+    for index in 0..frame_count {
+        *allocator.frame(index) = Frame::RESERVED;
+    }
+
+    for (start, end) in usable() {
+        if start < table_end && table_start < end {
+            allocator.free_range(start, table_start.saturating_sub(start));
+            allocator.free_range(table_end, end.saturating_sub(table_end));
+        } else {
+            allocator.free_range(start, end - start);
         }
+    }
+
+    allocator.free = usable()
+        .map(|(start, end)| (end - start) as u64)
+        .sum::<u64>()
+        - table_pages as u64;
+    allocator.reserved = allocator.total - allocator.free;
+}
+
+/// Returns the current physical memory usage.
+pub fn statistics() -> MemoryStatistics {
+    let allocator = FRAMES.lock();
+
+    MemoryStatistics {
+        total: allocator.total,
+        free: allocator.free,
+        reserved: allocator.reserved,
+        kernel_heap: allocator.used[Owner::KernelHeap as usize],
+        page_tables: allocator.used[Owner::PageTable as usize],
+        user: allocator.used[Owner::User as usize],
+    }
+}
+
+/// Allocates a number of free pages given by amount and zeros them out.
+/// Returns the start of the physical address, or zero if no block is free.
+pub fn allocate_page(amount: u64, owner: Owner) -> usize {
+    allocate_aligned(amount, 1, owner)
+}
 
-        let page = (physical_address + PHYSICAL_OFFSET) as *mut UnallocatedPage;
-        (*page).next = cursor;
-        (*page).size = amount;
+/// Allocates `amount` contiguous zeroed pages whose physical address is a
+/// multiple of `alignment` pages, which must be a power of two. Used for huge
+/// pages and DMA buffers. Returns zero if no suitable block is free.
+pub fn allocate_aligned(amount: u64, alignment: u64, owner: Owner) -> usize {
+    assert!(amount > 0, "Cannot allocate zero pages");
+    assert!(
+        alignment.is_power_of_two(),
+        "Alignment must be a power of two"
+    );
 
-        match trailing {
-            None => *lock = page,
-            Some(previous) => (*previous).next = page,
+    let order = order_for(amount).max(order_for(alignment));
+    if order > MAX_ORDER {
+        return 0;
+    }
+
+    let mut allocator = FRAMES.lock();
+    let Some(index) = allocator.allocate(order) else {
+        return 0; // No sufficient space was found
+    };
+
+    // Give back the tail of the block that was only taken to round up.
+    allocator.free_range(index + amount as usize, (1 << order) - amount as usize);
+
+    for frame in index..index + amount as usize {
+        let frame = allocator.frame(frame);
+        frame.owner = owner;
+        frame.references = 1;
+    }
+
+    allocator.free -= amount;
+    allocator.used[owner as usize] += amount;
+    drop(allocator);
+
+    let address = index as u64 * PAGE_SIZE;
+    unsafe {
+        core::ptr::write_bytes(
+            (address + PHYSICAL_OFFSET) as *mut u8,
+            0,
+            (amount * PAGE_SIZE) as usize,
+        );
+    }
+
+    address as usize
+}
+
+/// Drops a reference to each of the `amount` pages starting at
+/// `physical_address`. Pages without any references left are returned to the
+/// allocator and merged with their free neighbours.
+pub unsafe fn free_page(physical_address: u64, amount: u64) {
+    assert!(
+        physical_address % PAGE_SIZE == 0,
+        "Freed address must be page-aligned"
+    );
+
+    let mut allocator = FRAMES.lock();
+    let start = (physical_address / PAGE_SIZE) as usize;
+    let end = start + amount as usize;
+    let mut run_start = start;
+
+    for index in start..=end {
+        let released = index < end && {
+            let frame = allocator.frame(index);
+            assert!(frame.references > 0, "Double free of frame {:#x}", index);
+
+            frame.references -= 1;
+            frame.references == 0
+        };
+
+        if released {
+            let owner = allocator.frame(index).owner;
+            allocator.used[owner as usize] -= 1;
+            allocator.free += 1;
+            continue;
         }
+
+        // Free each run of released pages in one go.
+        allocator.free_range(run_start, index - run_start);
+        run_start = index + 1;
     }
 }
+
+/// Adds a reference to each of the `amount` pages starting at
+/// `physical_address`, so that they stay allocated until `free_page` has
+/// been called once more for each of them.
+pub fn share_page(physical_address: u64, amount: u64) {
+    let mut allocator = FRAMES.lock();
+    let start = (physical_address / PAGE_SIZE) as usize;
+
+    for index in start..start + amount as usize {
+        let frame = allocator.frame(index);
+        assert!(frame.references > 0, "Sharing free frame {:#x}", index);
+
+        frame.references = frame
+            .references
+            .checked_add(1)
+            .expect("Too many references to a frame");
+    }
+}
+
+/// Returns the number of references held to the page at `physical_address`.
+pub fn references(physical_address: u64) -> u32 {
+    FRAMES
+        .lock()
+        .frame((physical_address / PAGE_SIZE) as usize)
+        .references
+}
@@ -1,116 +1,583 @@
-use crate::{allocator::allocate_page, paging};
+use alloc::vec::Vec;
+
+use xmas_elf::{
+    dynamic::Tag,
+    header::{self, Class, Data, Machine},
+    program::{self, ProgramHeader, ProgramHeader64, SegmentData, FLAG_R, FLAG_W, FLAG_X},
+    ElfFile, P64,
+};
+
+use crate::{
+    address_space::{
+        self, AddressSpace, RegionKind, PROGRAM_AREA, PROT_EXEC, PROT_READ, PROT_WRITE,
+    },
+    allocator::{allocate_aligned, free_page, Owner},
+    paging::{Flags, PageSize},
+    random, PHYSICAL_OFFSET, USER_END,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ELFError {
+    /// The file is too short to hold an ELF header.
     Missing,
+    /// The file must be 8-byte aligned in memory to be parsed in place.
+    UnalignedBuffer,
     WrongMagic,
+    /// The file is not a 64-bit ELF file.
+    WrongClass,
+    /// The file is not little-endian.
+    WrongEndian,
     WrongMachine,
     WrongType,
+    /// The headers are inconsistent, as reported by `xmas_elf`.
+    Malformed(&'static str),
+    /// The size of a program header is not the one for 64-bit files.
+    BadProgramHeaderSize,
+    /// The program header table does not fit in the file.
+    ProgramHeadersOutOfBounds,
+    /// The program header table is not 8-byte aligned.
+    MisalignedProgramHeaders,
+    /// The contents of a segment do not fit in the file.
+    SegmentOutOfBounds,
+    /// A segment has more bytes in the file than in memory.
+    FileSizeExceedsMemorySize,
+    /// A segment does not lie entirely in the user half.
+    SegmentOutsideUserSpace,
+    /// A segment's address and file offset do not agree modulo its alignment,
+    /// or the alignment is not a power of two.
+    MisalignedSegment,
+    /// Segments overlap or are not sorted by address.
+    OverlappingSegments,
+    /// The page holding the end of one segment and the start of the next
+    /// could not be given the permissions of both.
+    SharedSegmentPage,
+    /// The entry point is not in an executable segment.
+    EntryOutsideSegments,
+    /// A segment asks to be both writable and executable without opting in.
+    WritableAndExecutable,
+    /// The dynamic segment or the relocation table it points to is
+    /// malformed.
+    BadDynamicSegment,
+    /// A relocation other than `R_X86_64_RELATIVE`, of the given type.
+    UnsupportedRelocation(u32),
+    /// A relocation that does not patch 8 aligned bytes of a loaded segment.
+    BadRelocation,
+    /// The interpreter path is not valid UTF-8, lies outside the file or
+    /// names no file in the ramdisk.
+    BadInterpreter,
+    /// The `PT_TLS` segment lies outside the file, has more bytes in the file
+    /// than in memory or an alignment that is not a power of two.
+    BadThreadLocalStorage,
+    /// No free range of the address space can hold the program.
+    NoRoom,
+    OutOfMemory,
+}
+
+/// Where a loaded program lies in memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Program<'a> {
+    pub entry: u64,
+    /// How far above the addresses it was linked at the program was loaded,
+    /// which is zero unless it is position-independent.
+    pub base: usize,
+    /// The first page past the highest loaded segment.
+    pub end: usize,
+    /// The address of the program headers in memory, or zero if they are
+    /// not part of a loaded segment.
+    pub program_headers: u64,
+    pub program_header_count: u16,
+    /// The path of the interpreter asked for by a `PT_INTERP` segment, which
+    /// must be loaded to run the program.
+    pub interpreter: Option<&'a str>,
+    /// The initial contents of each thread's TLS block, from a `PT_TLS`
+    /// segment.
+    pub tls: Option<TlsTemplate<'a>>,
 }
 
-#[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub struct Header {
-    pub magic: u32,
-    pub bitsize: u8,
-    pub endian: u8,
-    pub ident_abi_version: u8,
-    pub target_platform: u8,
-    pub abi_version: u8,
-    pub padding: [u8; 7],
-    pub obj_type: u16,
-    // 0xf3 for RISC-V 0x3e for x86_64
-    pub machine: u16,
-    pub version: u32,
-    pub entry_addr: usize,
-    pub program_header_offset: usize,
-    pub shoff: usize,
-    pub flags: u32,
-    pub ehsize: u16,
-    pub phentsize: u16,
-    pub phnum: u16,
-    pub shentsize: u16,
-    pub shnum: u16,
-    pub shstrndx: u16,
+/// The initial contents of a thread-local storage block.
+#[derive(Debug, Clone, Copy)]
+pub struct TlsTemplate<'a> {
+    /// The initialized data, which the rest of the block follows as zeroes.
+    pub data: &'a [u8],
+    pub size: usize,
+    pub align: usize,
 }
 
-const MAGIC: u32 = 0x464c457f;
-const X86_64: u16 = 0x3e;
+/// The size of a program header, as passed to programs in `AT_PHENT`.
+pub const PROGRAM_HEADER_SIZE: u64 = core::mem::size_of::<ProgramHeader64>() as u64;
 
-const EXECUTABLE: u16 = 2;
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
 
-const PH_SEG_TYPE_LOAD: u32 = 1;
+/// The size of an `Elf64_Rela` entry.
+const RELA_SIZE: usize = 24;
 
-#[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub struct ProgramHeader {
-    pub seg_type: u32,
-    pub flags: u32,
-    pub off: usize,
-    pub vaddr: usize,
-    pub paddr: usize,
-    pub filesz: usize,
-    pub memsz: usize,
-    pub align: usize,
-}
+/// An OS-specific flag that allows a segment to be both writable and
+/// executable, which is refused otherwise.
+const FLAG_WRITE_EXECUTE: u32 = 0x0010_0000;
 
-pub fn load_program(buffer: &[u8], page_table: &mut paging::Table) -> Result<u64, ELFError> {
-    if buffer.len() < core::mem::size_of::<Header>() {
-        return Err(ELFError::Missing);
+/// Returns the page flags for a segment with the given `p_flags`.
+fn segment_flags(ph_flags: u32) -> Result<Flags, ELFError> {
+    if ph_flags & (FLAG_W | FLAG_X) == FLAG_W | FLAG_X && ph_flags & FLAG_WRITE_EXECUTE == 0 {
+        return Err(ELFError::WritableAndExecutable);
     }
 
-    let header = unsafe { (buffer.as_ptr() as *const Header).as_ref().unwrap() };
+    let mut protection = 0;
+    if ph_flags & FLAG_R != 0 {
+        protection |= PROT_READ;
+    }
+    if ph_flags & FLAG_W != 0 {
+        protection |= PROT_WRITE;
+    }
+    if ph_flags & FLAG_X != 0 {
+        protection |= PROT_EXEC;
+    }
 
-    if header.magic != MAGIC {
-        return Err(ELFError::WrongMagic);
+    Ok(address_space::protection_flags(protection).unwrap())
+}
+
+fn page_end(address: usize) -> usize {
+    address.next_multiple_of(4096)
+}
+
+/// Checks that a table of `count` entries of `size` bytes at `offset` fits
+/// in the file.
+fn table_fits(file: &ElfFile, offset: u64, size: u16, count: u16) -> bool {
+    offset
+        .checked_add(size as u64 * count as u64)
+        .is_some_and(|end| end <= file.input.len() as u64)
+}
+
+/// Checks the file header and that the program headers can be read.
+fn check_header(file: &ElfFile) -> Result<(), ELFError> {
+    let pt2 = &file.header.pt2;
+
+    if file.header.pt1.class() != Class::SixtyFour {
+        return Err(ELFError::WrongClass);
+    }
+
+    if file.header.pt1.data() != Data::LittleEndian {
+        return Err(ELFError::WrongEndian);
     }
 
-    if header.machine != X86_64 {
+    if pt2.machine().as_machine() != Machine::X86_64 {
         return Err(ELFError::WrongMachine);
     }
 
-    if header.obj_type != EXECUTABLE {
+    if !matches!(
+        pt2.type_().as_type(),
+        header::Type::Executable | header::Type::SharedObject
+    ) {
         return Err(ELFError::WrongType);
     }
 
-    let ph_tab =
-        unsafe { buffer.as_ptr().add(header.program_header_offset) as *const ProgramHeader };
+    if pt2.ph_entry_size() as usize != core::mem::size_of::<ProgramHeader64>() {
+        return Err(ELFError::BadProgramHeaderSize);
+    }
+
+    if !table_fits(file, pt2.ph_offset(), pt2.ph_entry_size(), pt2.ph_count()) {
+        return Err(ELFError::ProgramHeadersOutOfBounds);
+    }
 
-    for i in 0..header.phnum as usize {
-        let program_header = unsafe { ph_tab.add(i).as_ref().unwrap() };
+    if pt2.ph_offset() % 8 != 0 {
+        return Err(ELFError::MisalignedProgramHeaders);
+    }
+
+    // The section header table is checked by `sanity_check` below, which
+    // would overflow on absurd offsets.
+    if !table_fits(file, pt2.sh_offset(), pt2.sh_entry_size(), pt2.sh_count()) {
+        return Err(ELFError::Malformed("section header table out of range"));
+    }
+
+    header::sanity_check(file).map_err(ELFError::Malformed)
+}
+
+/// Checks that a segment's file contents fit in the file.
+fn in_file(file: &ElfFile, segment: &ProgramHeader64) -> bool {
+    segment
+        .offset
+        .checked_add(segment.file_size)
+        .is_some_and(|end| end <= file.input.len() as u64)
+}
 
-        if program_header.seg_type != PH_SEG_TYPE_LOAD {
-            continue;
+/// Checks that a loadable segment fits in the file and the user half, and
+/// that it starts past the end of the segment before it, if any.
+fn check_segment(
+    file: &ElfFile,
+    segment: &ProgramHeader64,
+    previous: Option<&ProgramHeader64>,
+) -> Result<(), ELFError> {
+    if segment.file_size > segment.mem_size {
+        return Err(ELFError::FileSizeExceedsMemorySize);
+    }
+
+    if !in_file(file, segment) {
+        return Err(ELFError::SegmentOutOfBounds);
+    }
+
+    if segment
+        .virtual_addr
+        .checked_add(segment.mem_size)
+        .is_none_or(|end| end > USER_END as u64)
+    {
+        return Err(ELFError::SegmentOutsideUserSpace);
+    }
+
+    if segment.align > 1
+        && (!segment.align.is_power_of_two()
+            || segment.virtual_addr % segment.align != segment.offset % segment.align)
+    {
+        return Err(ELFError::MisalignedSegment);
+    }
+
+    segment_flags(segment.flags.0)?;
+
+    if let Some(previous) = previous {
+        let previous_end = (previous.virtual_addr + previous.mem_size) as usize;
+        if (segment.virtual_addr as usize) < previous_end {
+            return Err(ELFError::OverlappingSegments);
         }
 
-        if program_header.memsz == 0 {
-            continue;
+        // A page holding the end of one segment and the start of the next
+        // is mapped with the permissions of both.
+        if page_end(previous_end) > segment.virtual_addr as usize & !0xfff {
+            segment_flags(previous.flags.0 | segment.flags.0)?;
         }
+    }
+
+    Ok(())
+}
+
+/// Copies the part of `segment`'s file contents that falls in the page at
+/// `address`, backed by the frame at `physical_address`. The rest of the
+/// segment, such as `.bss`, stays zero since frames are zeroed when
+/// allocated.
+unsafe fn copy_segment(
+    file: &ElfFile,
+    segment: &ProgramHeader64,
+    bias: usize,
+    address: usize,
+    physical_address: usize,
+    size: PageSize,
+) {
+    let data = segment.raw_data(file);
+    let vaddr = segment.virtual_addr as usize + bias;
+
+    let start = address.max(vaddr);
+    let end = (address + size.bytes()).min(vaddr + data.len());
+
+    if start < end {
+        // The page may be read-only for the process, so it is written
+        // through the physical memory window.
+        core::ptr::copy_nonoverlapping(
+            data.as_ptr().add(start - vaddr),
+            (physical_address as u64 + PHYSICAL_OFFSET + (start - address) as u64) as *mut u8,
+            end - start,
+        );
+    }
+}
+
+/// Applies the relocations of a position-independent executable without an
+/// interpreter, loaded `bias` bytes above the addresses it was linked at.
+/// Only relative relocations are supported, since there is nothing to link
+/// against.
+fn relocate(
+    file: &ElfFile,
+    segments: &[&ProgramHeader64],
+    bias: usize,
+    address_space: &mut AddressSpace,
+) -> Result<(), ELFError> {
+    let Some(dynamic) = file
+        .program_iter()
+        .find(|program_header| program_header.get_type() == Ok(program::Type::Dynamic))
+    else {
+        return Ok(());
+    };
+
+    let ProgramHeader::Ph64(dynamic) = dynamic else {
+        return Err(ELFError::WrongClass);
+    };
+
+    // `get_data` reads the entries in place and panics on a bad size.
+    if !in_file(file, dynamic) || dynamic.offset % 8 != 0 || dynamic.file_size % 16 != 0 {
+        return Err(ELFError::BadDynamicSegment);
+    }
+
+    let Ok(SegmentData::Dynamic64(entries)) = dynamic.get_data(file) else {
+        return Err(ELFError::BadDynamicSegment);
+    };
+
+    let mut table = None;
+    let mut table_size = 0;
 
-        let page_count = (program_header.vaddr - (program_header.vaddr) / 4096 * 4096
-            + program_header.memsz
-            + 4095)
-            / 4096;
-        let page = allocate_page(page_count as u64);
-
-        unsafe {
-            let start = (program_header.vaddr / 4096) * 4096;
-            for i in 0..page_count {
-                // TODO: Map page with proper flags.
-                page_table.create_mapping(start + i * 4096, page + i * 4096, paging::Flags::ALL);
+    for entry in entries {
+        match entry.get_tag() {
+            Ok(Tag::Null) => break,
+            Ok(Tag::Rela) => table = entry.get_ptr().ok(),
+            Ok(Tag::RelaSize) => table_size = entry.get_val().unwrap_or(0),
+            Ok(Tag::RelaEnt) if entry.get_val() != Ok(RELA_SIZE as u64) => {
+                return Err(ELFError::BadDynamicSegment);
             }
+            _ => {}
+        }
+    }
+
+    let Some(table) = table else {
+        return Ok(());
+    };
+
+    // The table is given by address, so find it in the file through the
+    // segment that loads it.
+    let offset = segments
+        .iter()
+        .find(|segment| {
+            (segment.virtual_addr..segment.virtual_addr + segment.file_size).contains(&table)
+        })
+        .map(|segment| (table - segment.virtual_addr + segment.offset) as usize)
+        .ok_or(ELFError::BadDynamicSegment)?;
+
+    let relocations = file
+        .input
+        .get(offset..)
+        .and_then(|input| input.get(..table_size as usize))
+        .ok_or(ELFError::BadDynamicSegment)?;
+
+    for relocation in relocations.chunks_exact(RELA_SIZE) {
+        let field = |index: usize| {
+            u64::from_le_bytes(relocation[index * 8..index * 8 + 8].try_into().unwrap())
+        };
+
+        let address = (field(0) as usize).wrapping_add(bias);
+        let value = field(2).wrapping_add(bias as u64);
 
-            core::ptr::copy_nonoverlapping(
-                buffer.as_ptr().add(program_header.off),
-                program_header.vaddr as *mut u8,
-                program_header.memsz,
-            );
+        match field(1) as u32 {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                let in_program = address_space.find_region(address).is_some_and(|region| {
+                    matches!(region.kind, RegionKind::Code | RegionKind::Data)
+                });
+
+                if address % 8 != 0 || !in_program {
+                    return Err(ELFError::BadRelocation);
+                }
+
+                // Written through the physical memory window, as relocated
+                // data may be read-only.
+                let page = address_space.table().translate(address).unwrap();
+                unsafe { *((page as u64 + PHYSICAL_OFFSET) as *mut u64) = value };
+            }
+            other => return Err(ELFError::UnsupportedRelocation(other)),
         }
     }
 
-    println!("Done mapping elf!");
+    Ok(())
+}
+
+/// Picks a random multiple of 2 MiB that moves `low..high` to a free part
+/// of `PROGRAM_AREA`, so that huge pages can still be used.
+fn random_bias(address_space: &AddressSpace, low: usize, high: usize) -> Option<usize> {
+    let huge = PageSize::Size2MiB.bytes();
+    let low = low & !(huge - 1);
+    let span = (high - low).next_multiple_of(huge);
+
+    if span >= PROGRAM_AREA.end - PROGRAM_AREA.start {
+        return None;
+    }
+
+    (0..16)
+        .filter_map(|_| {
+            random::random_address(PROGRAM_AREA.start, PROGRAM_AREA.end - span, huge)
+                .checked_sub(low)
+        })
+        .find(|bias| address_space.is_free(low + bias, high + bias))
+}
+
+pub fn load_program<'a>(
+    buffer: &'a [u8],
+    address_space: &mut AddressSpace,
+) -> Result<Program<'a>, ELFError> {
+    if buffer.as_ptr() as usize % 8 != 0 {
+        return Err(ELFError::UnalignedBuffer);
+    }
+
+    let header_size =
+        core::mem::size_of::<header::HeaderPt1>() + core::mem::size_of::<header::HeaderPt2_<P64>>();
+    if buffer.len() < header_size {
+        return Err(ELFError::Missing);
+    }
+
+    if !buffer.starts_with(&header::MAGIC) {
+        return Err(ELFError::WrongMagic);
+    }
+
+    let file = ElfFile::new(buffer).map_err(ELFError::Malformed)?;
+
+    check_header(&file)?;
+
+    let mut segments: Vec<&ProgramHeader64> = Vec::new();
+    let mut program_headers = None;
+    let mut interpreter = None;
+    let mut tls = None;
+
+    for program_header in file.program_iter() {
+        let ProgramHeader::Ph64(program_header) = program_header else {
+            return Err(ELFError::WrongClass);
+        };
+
+        match program_header.get_type() {
+            Ok(program::Type::Load) if program_header.mem_size > 0 => {
+                check_segment(&file, program_header, segments.last().copied())?;
+                segments.push(program_header);
+            }
+            Ok(program::Type::Phdr) => program_headers = Some(program_header.virtual_addr),
+            Ok(program::Type::Interp) => {
+                if !in_file(&file, program_header) {
+                    return Err(ELFError::BadInterpreter);
+                }
+
+                let path = program_header.raw_data(&file);
+                let path = path.strip_suffix(&[0]).unwrap_or(path);
+                interpreter =
+                    Some(core::str::from_utf8(path).map_err(|_| ELFError::BadInterpreter)?);
+            }
+            Ok(program::Type::Tls) if program_header.mem_size > 0 => {
+                if !in_file(&file, program_header)
+                    || program_header.file_size > program_header.mem_size
+                    || !program_header.align.max(1).is_power_of_two()
+                {
+                    return Err(ELFError::BadThreadLocalStorage);
+                }
+
+                tls = Some(TlsTemplate {
+                    data: program_header.raw_data(&file),
+                    size: program_header.mem_size as usize,
+                    align: program_header.align.max(1) as usize,
+                });
+            }
+            _ => {}
+        }
+    }
 
-    unsafe { paging::Table::activate_kernel_table() };
+    let Some((first, last)) = segments.first().zip(segments.last()) else {
+        return Err(ELFError::EntryOutsideSegments);
+    };
+    let low = first.virtual_addr as usize & !0xfff;
+    let high = page_end((last.virtual_addr + last.mem_size) as usize);
+
+    // Position-independent executables are loaded at a random address.
+    let position_independent = file.header.pt2.type_().as_type() == header::Type::SharedObject;
+    let bias = match position_independent {
+        true => random_bias(address_space, low, high).ok_or(ELFError::NoRoom)?,
+        false if address_space.is_free(low, high) => 0,
+        false => return Err(ELFError::NoRoom),
+    };
+
+    // Without a `PT_PHDR` segment, the program headers are found through
+    // the segment that loads them, if any.
+    let program_headers = program_headers
+        .or_else(|| {
+            let offset = file.header.pt2.ph_offset();
+
+            segments
+                .iter()
+                .find(|segment| {
+                    (segment.offset..segment.offset + segment.file_size).contains(&offset)
+                })
+                .map(|segment| segment.virtual_addr + (offset - segment.offset))
+        })
+        .map_or(0, |address| address + bias as u64);
+
+    let entry = file.header.pt2.entry_point();
+    if !segments.iter().any(|segment| {
+        segment.flags.is_execute()
+            && (segment.virtual_addr..segment.virtual_addr + segment.mem_size).contains(&entry)
+    }) {
+        return Err(ELFError::EntryOutsideSegments);
+    }
+
+    let mut program_end = 0;
+    let mut previous: Option<&ProgramHeader64> = None;
+
+    for &segment in &segments {
+        let flags = segment_flags(segment.flags.0)?;
+        let mut start = (segment.virtual_addr as usize + bias) & !0xfff;
+        let end = page_end((segment.virtual_addr + segment.mem_size) as usize + bias);
+
+        // The first page is already mapped if it holds the end of the
+        // previous segment.
+        if let Some(previous) = previous.filter(|_| program_end > start) {
+            let flags = segment_flags(previous.flags.0 | segment.flags.0)?;
+            if !address_space.protect(start, 4096, flags) {
+                return Err(ELFError::SharedSegmentPage);
+            }
+
+            let page = address_space.table().translate(start).unwrap();
+            unsafe { copy_segment(&file, segment, bias, start, page, PageSize::Size4KiB) };
+
+            start += 4096;
+        }
+
+        let mut address = start;
+
+        // Cover as much of the segment as possible with 2 MiB pages.
+        while address < end {
+            let huge = PageSize::Size2MiB;
+            let size = if address % huge.bytes() == 0 && end - address >= huge.bytes() {
+                huge
+            } else {
+                PageSize::Size4KiB
+            };
+
+            let page = allocate_aligned(size.pages(), size.pages(), Owner::User);
+            if page == 0 {
+                return Err(ELFError::OutOfMemory);
+            }
+
+            unsafe {
+                let mapped = address_space
+                    .table()
+                    .create_sized_mapping(address, page, size, flags);
+
+                if !mapped {
+                    free_page(page as u64, size.pages());
+                    return Err(ELFError::OutOfMemory);
+                }
+
+                copy_segment(&file, segment, bias, address, page, size);
+            }
+
+            address += size.bytes();
+        }
+
+        if start < end {
+            let kind = if segment.flags.is_execute() {
+                RegionKind::Code
+            } else {
+                RegionKind::Data
+            };
+            address_space.add_region(start, end, kind, flags);
+        }
+
+        program_end = end;
+        previous = Some(segment);
+    }
+
+    // A program with an interpreter is relocated by it, and may need symbols
+    // the kernel cannot resolve. Interpreters and static executables only
+    // have relative relocations, which are applied here.
+    if position_independent && interpreter.is_none() {
+        relocate(&file, &segments, bias, address_space)?;
+    }
+
+    println!("Done mapping elf!");
 
-    Ok(header.entry_addr as u64)
+    Ok(Program {
+        entry: entry + bias as u64,
+        base: bias,
+        end: program_end,
+        program_headers,
+        program_header_count: file.header.pt2.ph_count(),
+        interpreter,
+        tls,
+    })
 }
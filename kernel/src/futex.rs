@@ -0,0 +1,56 @@
+use slab::Slab;
+
+use crate::{
+    scheduling,
+    syscall::{SyscallError, SystemCallFrame},
+    Core, Memory, Process, Task, PROCESSES,
+};
+
+/// Blocks the running thread on the `u32` at `address` until another thread
+/// of its process wakes it, returning zero then. Fails with `EAGAIN` without
+/// blocking if the word no longer holds `expected`.
+pub fn wait(frame: &SystemCallFrame, address: u64, expected: u32) -> Result<u64, SyscallError> {
+    if address % 4 != 0 {
+        return Err(SyscallError::EINVAL);
+    }
+
+    {
+        let mut processes = PROCESSES.lock();
+        let mut value = [0; 4];
+
+        if !Memory::current(&mut processes)
+            .address_space
+            .read(address as usize, &mut value)
+        {
+            return Err(SyscallError::EFAULT);
+        }
+
+        // Wakers run only once this thread is switched away from, since
+        // system calls run with interrupts disabled.
+        if u32::from_le_bytes(value) != expected {
+            return Err(SyscallError::EAGAIN);
+        }
+    }
+
+    unsafe { scheduling::suspend_current_thread(frame, 0, Some(address)) }
+}
+
+/// Queues up to `count` threads of process `pid` waiting on `address` to run
+/// again. Returns how many were woken.
+pub fn wake(processes: &mut Slab<Process>, pid: usize, address: u64, count: usize) -> usize {
+    let mut woken = 0;
+
+    for (_, thread) in processes.iter_mut() {
+        if woken == count {
+            break;
+        }
+
+        if thread.leader == pid && thread.waiting == Some(address) {
+            thread.waiting = None;
+            Core::local().queue.push_back(Task::from(&*thread));
+            woken += 1;
+        }
+    }
+
+    woken
+}
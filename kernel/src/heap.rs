@@ -0,0 +1,260 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    allocator::{allocate_aligned, free_page, Owner, PAGE_SIZE},
+    PHYSICAL_OFFSET,
+};
+
+/// Object sizes served from slab caches. Anything larger goes straight to
+/// the frame allocator.
+const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+#[global_allocator]
+static HEAP: KernelHeap = KernelHeap::new();
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// The header at the start of every slab, a naturally aligned block of pages
+/// split into objects of one size.
+struct Slab {
+    /// The free objects of this slab.
+    free: *mut FreeObject,
+    /// The number of objects handed out.
+    used: usize,
+    /// The neighbours on the cache's list of slabs with free objects.
+    previous: *mut Slab,
+    next: *mut Slab,
+}
+
+/// Slabs hold at least this many objects, so that the header wastes little.
+const MIN_OBJECTS: usize = 16;
+
+/// Objects of one size, carved out of slabs. Objects are aligned to their
+/// size since every slab is aligned to its own size and the header takes up
+/// a whole number of objects. A slab is returned to the frame allocator as
+/// soon as all of its objects are free.
+struct SlabCache {
+    size: usize,
+    /// The slabs with at least one free object.
+    partial: *mut Slab,
+}
+
+impl SlabCache {
+    const fn new(size: usize) -> Self {
+        SlabCache {
+            size,
+            partial: null_mut(),
+        }
+    }
+
+    fn pages(&self) -> u64 {
+        ((self.size * MIN_OBJECTS) as u64).div_ceil(PAGE_SIZE)
+    }
+
+    fn allocate(&mut self) -> *mut u8 {
+        if self.partial.is_null() {
+            self.grow();
+        }
+
+        let slab = self.partial;
+        if slab.is_null() {
+            return null_mut();
+        }
+
+        unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).used += 1;
+
+            if (*slab).free.is_null() {
+                self.unlink(slab);
+            }
+
+            object as *mut u8
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8) {
+        let slab = self.slab_of(ptr);
+
+        // A full slab has free objects again.
+        if (*slab).free.is_null() {
+            self.link(slab);
+        }
+
+        let object = ptr as *mut FreeObject;
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+        (*slab).used -= 1;
+
+        if (*slab).used == 0 {
+            self.unlink(slab);
+            free_page(slab as u64 - PHYSICAL_OFFSET, self.pages());
+        }
+    }
+
+    /// The slab holding `ptr`, found by rounding down to the slab size.
+    unsafe fn slab_of(&self, ptr: *mut u8) -> *mut Slab {
+        let bytes = self.pages() * PAGE_SIZE;
+        let physical_address = (ptr as u64 - PHYSICAL_OFFSET) & !(bytes - 1);
+
+        (physical_address + PHYSICAL_OFFSET) as *mut Slab
+    }
+
+    /// Allocates a fresh slab and puts all of its objects on its free list.
+    fn grow(&mut self) {
+        let pages = self.pages();
+        let page = allocate_aligned(pages, pages, Owner::KernelHeap);
+        if page == 0 {
+            return;
+        }
+
+        let base = page + unsafe { PHYSICAL_OFFSET } as usize;
+        let slab = base as *mut Slab;
+        let first = self.size.max(core::mem::size_of::<Slab>());
+
+        unsafe {
+            slab.write(Slab {
+                free: null_mut(),
+                used: 0,
+                previous: null_mut(),
+                next: null_mut(),
+            });
+
+            for offset in (first..(pages * PAGE_SIZE) as usize)
+                .step_by(self.size)
+                .rev()
+            {
+                let object = (base + offset) as *mut FreeObject;
+                (*object).next = (*slab).free;
+                (*slab).free = object;
+            }
+
+            self.link(slab);
+        }
+    }
+
+    unsafe fn link(&mut self, slab: *mut Slab) {
+        (*slab).previous = null_mut();
+        (*slab).next = self.partial;
+
+        if !self.partial.is_null() {
+            (*self.partial).previous = slab;
+        }
+
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        let Slab { previous, next, .. } = *slab;
+
+        if previous.is_null() {
+            self.partial = next;
+        } else {
+            (*previous).next = next;
+        }
+
+        if !next.is_null() {
+            (*next).previous = previous;
+        }
+    }
+}
+
+unsafe impl Send for SlabCache {}
+
+/// Where an allocation with a given layout is served from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Slab(usize),
+    /// Contiguous frames accessed through the physical memory window.
+    Pages(u64),
+}
+
+impl Class {
+    fn of(layout: Layout) -> Self {
+        let size = layout.size().max(layout.align());
+
+        match SIZE_CLASSES.iter().position(|&class| class >= size) {
+            Some(index) => Class::Slab(index),
+            None => Class::Pages((layout.size() as u64).div_ceil(PAGE_SIZE)),
+        }
+    }
+}
+
+/// The kernel's global allocator.
+pub struct KernelHeap {
+    caches: Spinlock<[SlabCache; SIZE_CLASSES.len()]>,
+}
+
+impl KernelHeap {
+    const fn new() -> Self {
+        let mut caches = [const { SlabCache::new(0) }; SIZE_CLASSES.len()];
+
+        let mut index = 0;
+        while index < SIZE_CLASSES.len() {
+            caches[index].size = SIZE_CLASSES[index];
+            index += 1;
+        }
+
+        KernelHeap {
+            caches: Spinlock::new(caches),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The timer interrupt allocates when requeueing, so the locks below
+        // must never be held with interrupts enabled.
+        interrupts::without_interrupts(|| match Class::of(layout) {
+            Class::Slab(index) => self.caches.lock()[index].allocate(),
+            Class::Pages(pages) => {
+                let alignment = (layout.align() as u64)
+                    .div_ceil(PAGE_SIZE)
+                    .next_power_of_two();
+
+                match allocate_aligned(pages, alignment, Owner::KernelHeap) {
+                    0 => null_mut(),
+                    page => (page as u64 + PHYSICAL_OFFSET) as *mut u8,
+                }
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| match Class::of(layout) {
+            Class::Slab(index) => self.caches.lock()[index].deallocate(ptr),
+            Class::Pages(pages) => free_page(ptr as u64 - PHYSICAL_OFFSET, pages),
+        })
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        match (Class::of(layout), Class::of(new_layout)) {
+            (old, new) if old == new => return ptr,
+            // Shrinking a page allocation just gives back the tail.
+            (Class::Pages(old), Class::Pages(new)) if new < old => {
+                interrupts::without_interrupts(|| {
+                    free_page(ptr as u64 - PHYSICAL_OFFSET + new * PAGE_SIZE, old - new)
+                });
+                return ptr;
+            }
+            _ => {}
+        }
+
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+
+        new_ptr
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{gdt::GDT, hlt_loop, println};
+use crate::{gdt::GDT, hlt_loop, println, scheduling, Core, Memory, KERNEL_START, PROCESSES};
 use conquer_once::spin::Lazy;
 use core::arch::asm;
 use pic8259::ChainedPics;
@@ -67,8 +67,8 @@ static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
 
     unsafe {
         idt.slice_mut(InterruptIndex::Timer.as_u8()..=InterruptIndex::Timer.as_u8())[0]
-            .set_handler_addr(VirtAddr::new(
-                crate::scheduling::timer_interrupt_handler as u64,
+            .set_handler_addr(VirtAddr::from_ptr(
+                crate::scheduling::timer_interrupt_handler as *const (),
             ))
             .set_code_selector(GDT.1.code_selector)
             .set_privilege_level(x86_64::PrivilegeLevel::Ring0)
@@ -178,6 +178,47 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    let address = Cr2::read_raw() as usize;
+
+    // Faults in user memory are expected, whether from the process itself or
+    // from the kernel accessing user memory in a system call. They are either
+    // resolved or end the process.
+    if address < KERNEL_START as usize {
+        let result = {
+            let mut processes = PROCESSES.lock();
+
+            processes.contains(Core::local().current_thread).then(|| {
+                Memory::current(&mut processes)
+                    .address_space
+                    .handle_page_fault(
+                        address,
+                        error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+                        error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+                    )
+            })
+        };
+
+        match result {
+            Some(Ok(())) => return,
+            Some(Err(error)) => unsafe {
+                scheduling::kill_current_process(format_args!(
+                    "{} at {:#x} ({:?})",
+                    error, address, error_code
+                ))
+            },
+            None => {}
+        }
+    }
+
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        unsafe {
+            scheduling::kill_current_process(format_args!(
+                "page fault at {:#x} ({:?})",
+                address, error_code
+            ))
+        }
+    }
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
@@ -1,24 +1,20 @@
 #![no_std]
 #![no_main]
-#![feature(
-    const_mut_refs,
-    abi_x86_interrupt,
-    str_from_raw_parts,
-    naked_functions,
-    asm_const,
-    allocator_api
-)]
+#![feature(const_mut_refs, abi_x86_interrupt, naked_functions, asm_const)]
 #![allow(clippy::upper_case_acronyms)]
 
 extern crate alloc;
 
 use core::arch::asm;
 
-use alloc::collections::VecDeque;
-use alloc::vec::Vec;
-use bootloader_api::{entry_point, BootInfo};
+use alloc::{boxed::Box, collections::VecDeque, vec};
+use bootloader_api::{entry_point, info::MemoryRegionKind, BootInfo};
 
 const KERNEL_START: u64 = 0xFFFF_8000_0000_0000;
+/// The first address past user memory. The last page of the lower half is
+/// left out, so that a `syscall` at its very end cannot make `sysretq` return
+/// to a non-canonical address, which faults in the kernel.
+const USER_END: usize = 0x0000_7FFF_FFFF_F000;
 
 #[macro_export]
 macro_rules! println {
@@ -55,13 +51,15 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 
 use bootloader_api::config::{BootloaderConfig, Mapping};
 use conquer_once::spin::OnceCell;
+use slab::Slab;
 use spinning_top::Spinlock;
 use x86_64::registers::rflags::RFlags;
 use x86_64::VirtAddr;
 
-use crate::allocator::allocate_page;
+use crate::address_space::{AddressSpace, STACK_AREA};
+use crate::elf::{ELFError, TlsTemplate};
 use crate::gdt::GDT;
-use crate::paging::VirtualAllocator;
+use crate::syscall::{SyscallError, SystemCallFrame};
 
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
@@ -73,14 +71,25 @@ pub static BOOTLOADER_CONFIG: BootloaderConfig = {
 
 entry_point!(main, config = &BOOTLOADER_CONFIG);
 
+mod address_space;
 mod allocator;
 mod apic;
 mod elf;
 mod framebuffer;
+mod futex;
 mod gdt;
+mod heap;
 mod interrupts;
 mod paging;
+mod ramdisk;
+mod random;
 mod scheduling;
+mod shared_memory;
+mod syscall;
+mod time;
+mod tls;
+mod user_memory;
+mod user_stack;
 
 const CPUID_FEAT_EDX_APIC: u32 = 1 << 9;
 static mut PHYSICAL_OFFSET: u64 = 0;
@@ -94,45 +103,6 @@ static KERNEL_OFFSET: conquer_once::spin::OnceCell<u64> = conquer_once::spin::On
 
 use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
 
-unsafe extern "C" fn system_call_handler(
-    r1: u64,
-    r2: u64,
-    _r3: u64,
-    _r4: u64,
-    _r5: u64,
-    _r6: u64,
-    code: u64,
-) {
-    match code {
-        0 => {
-            println!("Process exited with code: {}!", r1);
-            hlt_loop();
-        }
-        1 => unsafe {
-            print!(
-                "{}",
-                core::str::from_raw_parts(r1 as *const u8, r2 as usize)
-            );
-        },
-        _ => panic!("Unknown system call with code: {}", code),
-    }
-}
-
-#[naked]
-unsafe extern "C" fn dispatch_system_call() -> ! {
-    asm!(
-        "push rcx",
-        "push rax",
-        "mov rcx, r10",
-        "call {}",
-        "add rsp, 8",
-        "pop rcx",
-        "sysretq",
-        sym system_call_handler,
-        options(noreturn)
-    )
-}
-
 fn initialize_usermode() {
     // Set up STAR, LSTAR, and SFMASK MSRs for sysret.
     // STAR: Segment selectors for sysret (user code and data segments).
@@ -145,13 +115,16 @@ fn initialize_usermode() {
         GDT.1.data_selector,
     )
     .unwrap();
-    LStar::write(VirtAddr::new(dispatch_system_call as u64)); // Syscall target address, not relevant for sysret
+    LStar::write(VirtAddr::from_ptr(
+        syscall::dispatch_system_call as *const (),
+    )); // Syscall target address, not relevant for sysret
     SFMask::write(RFlags::INTERRUPT_FLAG);
 
-    // Enable system call extensions.
+    // Enable system call extensions, and the no-execute bit in page tables
+    // so that user pages can be mapped without execute permission.
     unsafe {
         Efer::update(|flags| {
-            *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS;
+            *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS | EferFlags::NO_EXECUTE_ENABLE;
         });
     }
 }
@@ -179,15 +152,18 @@ pub fn initialize(boot_info: &'static mut BootInfo) {
     }
 
     KERNEL_OFFSET.init_once(|| boot_info.kernel_image_offset);
+    ramdisk::initialize(boot_info);
 
     framebuffer::initialize(boot_info.framebuffer.as_mut().unwrap());
     gdt::init();
     interrupts::init_idt();
+    time::initialize();
 
     let supports_apic = (unsafe { core::arch::x86_64::__cpuid(1) }.edx & CPUID_FEAT_EDX_APIC) != 0;
     assert!(supports_apic);
 
     allocator::initialize(&boot_info.memory_regions);
+    println!("{}", allocator::statistics());
 
     let mut cr3: u64;
     unsafe {
@@ -200,9 +176,28 @@ pub fn initialize(boot_info: &'static mut BootInfo) {
             .unwrap()
     };
 
+    // Switch the physical memory window over to huge pages. The bootloader
+    // maps it with small pages, which wastes table memory and TLB entries.
+    let physical_memory_end = boot_info
+        .memory_regions
+        .iter()
+        .filter(|region| region.kind == MemoryRegionKind::Usable)
+        .map(|region| region.end)
+        .max()
+        .unwrap();
+
+    unsafe {
+        page_table.remap_huge(
+            PHYSICAL_OFFSET as usize,
+            0,
+            physical_memory_end as usize,
+            paging::Flags::WRITE,
+        );
+    }
+
     unsafe {
         let apic_address = 0xfee0_0000usize;
-        page_table.create_mapping(apic_address, apic_address, paging::Flags::WRITE);
+        assert!(page_table.create_mapping(apic_address, apic_address, paging::Flags::WRITE));
         apic::initialize(apic_address);
     }
 
@@ -240,11 +235,17 @@ impl Ord for Task {
     }
 }
 
-/// Storage for variables for each core
+/// Storage for variables for each core. `dispatch_system_call` finds it
+/// through the GS base, so the fields it uses come first.
+#[repr(C)]
 pub struct Core {
+    /// The top of the running thread's kernel stack.
+    kernel_stack: u64,
+    /// Where the user stack pointer is kept while switching stacks.
+    user_stack: u64,
     thread_started: u64,
     current_thread: usize,
-    queue: VecDeque<Task, VirtualAllocator>,
+    queue: VecDeque<Task>,
 }
 
 impl Default for Core {
@@ -260,9 +261,11 @@ impl Core {
 
     pub const fn new() -> Self {
         Core {
+            kernel_stack: 0,
+            user_stack: 0,
             thread_started: 0,
             current_thread: usize::MAX,
-            queue: VecDeque::new_in(VirtualAllocator),
+            queue: VecDeque::new(),
         }
     }
 }
@@ -286,68 +289,326 @@ struct Context {
     rsp: u64,
     rip: u64,
     eflags: u64,
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    /// The thread pointer, loaded into the FS base.
+    fs_base: u64,
+}
+
+impl Context {
+    /// The registers that resume a thread after the system call it made
+    /// with `frame`, returning `result`.
+    fn after_system_call(frame: &SystemCallFrame, result: u64, fs_base: u64) -> Self {
+        Context {
+            rax: result,
+            rdi: frame.rdi,
+            rsi: frame.rsi,
+            rdx: frame.rdx,
+            r8: frame.r8,
+            r9: frame.r9,
+            r10: frame.r10,
+            rsp: frame.rsp,
+            rip: frame.rip,
+            eflags: frame.rflags,
+            rbx: frame.rbx,
+            rbp: frame.rbp,
+            r12: frame.r12,
+            r13: frame.r13,
+            r14: frame.r14,
+            r15: frame.r15,
+            fs_base,
+            ..Context::default()
+        }
+    }
 }
 
+/// The largest the stack of a process can grow to.
+const STACK_SIZE: usize = 8 * 1024 * 1024;
+
+const KERNEL_STACK_SIZE: usize = 32 * 1024;
+
+/// The stack the system calls of a thread run on.
+struct KernelStack(Box<[u8]>);
+
+impl KernelStack {
+    fn new() -> Self {
+        KernelStack(vec![0; KERNEL_STACK_SIZE].into_boxed_slice())
+    }
+
+    /// The initial stack pointer, which is page aligned.
+    fn top(&self) -> u64 {
+        self.0.as_ptr_range().end as u64
+    }
+}
+
+impl core::fmt::Debug for KernelStack {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "KernelStack({:#x})", self.top())
+    }
+}
+
+/// A thread. Its pid is also the pid of the process if it is the first
+/// thread, which holds the memory of the process and lives as long as it.
 #[derive(Debug)]
 struct Process {
     pid: usize,
+    /// The pid of the process this thread belongs to.
+    leader: usize,
     /// If true then the process is returning from a syscall and can use sysretq rather than iretq
     fast_entry: bool,
     /// The number of clock cycles the process has used.
     elapsed: u64,
-    /// The pointer to the level-4 page table entry for this process.
-    cr3: u64,
+    /// The memory of the process, held by its first thread.
+    memory: Option<Memory>,
     /// The saved registers for this processes.
     state: Context,
+    kernel_stack: KernelStack,
+    /// The TLS block of a thread other than the first, unmapped when it
+    /// exits.
+    tls_block: Option<tls::Block>,
+    /// A word zeroed and woken as a futex when the thread exits, or zero.
+    exit_address: u64,
+    /// The futex address the thread is blocked on.
+    waiting: Option<u64>,
 }
 
-impl Process {
-    fn load(elf: &[u8], stack_start: usize) {
-        let table_ptr = paging::Table::new_copy();
-        let page_table = unsafe { table_ptr.as_mut().unwrap() };
+/// What the threads of a process share.
+#[derive(Debug)]
+struct Memory {
+    /// The user half of the page tables for this process.
+    address_space: AddressSpace,
+    /// The start of the heap, just past the program's highest segment.
+    heap_start: usize,
+    /// The end of the heap as set by `brk`, which need not be page-aligned.
+    program_break: usize,
+    /// The initial contents of the TLS block of each thread.
+    tls: Option<TlsTemplate<'static>>,
+}
 
-        unsafe {
-            paging::Table::activate(table_ptr);
+impl Memory {
+    /// The memory of the process the running thread belongs to.
+    fn current(processes: &mut Slab<Process>) -> &mut Memory {
+        Process::memory(processes, Core::local().current_thread)
+    }
+
+    /// Moves the program break to `address`, or leaves it if `address` is
+    /// below the start of the heap or the heap cannot grow that far. Returns
+    /// the new break.
+    fn set_break(&mut self, address: usize) -> usize {
+        let Some(new_end) = address.checked_next_multiple_of(4096) else {
+            return self.program_break;
+        };
+
+        if address < self.heap_start {
+            return self.program_break;
         }
 
-        let address = elf::load_program(elf, page_table).unwrap();
+        let old_end = self.program_break.next_multiple_of(4096);
 
-        unsafe {
-            page_table.create_mapping(stack_start, allocate_page(1), paging::Flags::ALL);
-            page_table.create_mapping(stack_start + 4096, allocate_page(1), paging::Flags::ALL);
+        if self
+            .address_space
+            .resize_heap(self.heap_start, old_end, new_end)
+        {
+            self.program_break = address;
         }
 
-        Process::launch(
-            address,
-            stack_start as u64 + 4096 + 4096,
-            table_ptr as u64 - unsafe { PHYSICAL_OFFSET },
+        self.program_break
+    }
+}
+
+impl Process {
+    /// Starts the program in `elf` with the given arguments and environment
+    /// strings. Returns the pid of the new process.
+    fn load(
+        elf: &'static [u8],
+        arguments: &[&str],
+        environment: &[&str],
+    ) -> Result<usize, ELFError> {
+        let mut address_space = AddressSpace::new().ok_or(ELFError::OutOfMemory)?;
+        let program = elf::load_program(elf, &mut address_space)?;
+
+        // A dynamically linked program is started through its interpreter,
+        // which finds the program through the auxiliary vector.
+        let (entry, interpreter_base) = match program.interpreter {
+            Some(path) => {
+                let file = ramdisk::find(path).ok_or(ELFError::BadInterpreter)?;
+                let interpreter = elf::load_program(file, &mut address_space)?;
+
+                (interpreter.entry, interpreter.base)
+            }
+            None => (program.entry, 0),
+        };
+
+        // A program linked at a fixed address may take up part of the stack
+        // area, so look for a free spot, guard page included.
+        let stack_length = 4096 + STACK_SIZE;
+        let stack_start = (0..16)
+            .map(|_| random::random_address(STACK_AREA.start, STACK_AREA.end - stack_length, 4096))
+            .find(|&start| address_space.is_free(start, start + stack_length))
+            .ok_or(ELFError::NoRoom)?;
+        let stack_top = address_space.reserve_stack(stack_start, STACK_SIZE);
+
+        let sp = user_stack::build(
+            &mut address_space,
+            stack_top,
+            arguments,
+            environment,
+            &[
+                (user_stack::AT_PHDR, program.program_headers),
+                (user_stack::AT_PHENT, elf::PROGRAM_HEADER_SIZE),
+                (user_stack::AT_PHNUM, program.program_header_count as u64),
+                (user_stack::AT_BASE, interpreter_base as u64),
+                (user_stack::AT_ENTRY, program.entry),
+            ],
         );
+
+        // The block of the first thread goes away with the address space.
+        let fs_base = match program.tls {
+            Some(template) => {
+                tls::create_block(&mut address_space, &template)
+                    .ok_or(ELFError::NoRoom)?
+                    .thread_pointer
+            }
+            None => 0,
+        };
+
+        let memory = Memory {
+            address_space,
+            heap_start: program.end,
+            program_break: program.end,
+            tls: program.tls,
+        };
+
+        Ok(Process::spawn(
+            None,
+            Some(memory),
+            Context {
+                rsp: sp as u64,
+                rip: entry,
+                fs_base,
+                ..Context::default()
+            },
+        ))
     }
 
-    fn launch(entry: u64, sp: u64, cr3: u64) {
+    /// Duplicates the process of the running thread, with only that thread.
+    /// The child resumes from the same system call with a return value of
+    /// zero, while the parent gets the child's pid.
+    fn fork(frame: &SystemCallFrame) -> Result<usize, SyscallError> {
+        let (memory, fs_base) = {
+            let mut processes = PROCESSES.lock();
+            let fs_base = processes[Core::local().current_thread].state.fs_base;
+            let parent = Memory::current(&mut processes);
+
+            let memory = Memory {
+                address_space: parent.address_space.fork().ok_or(SyscallError::ENOMEM)?,
+                heap_start: parent.heap_start,
+                program_break: parent.program_break,
+                tls: parent.tls,
+            };
+
+            (memory, fs_base)
+        };
+
+        Ok(Process::spawn(
+            None,
+            Some(memory),
+            Context::after_system_call(frame, 0, fs_base),
+        ))
+    }
+
+    /// Starts a thread in the process of the running thread, at `entry` with
+    /// `argument` in `rdi` and the stack pointer at `stack`. Returns its pid.
+    fn spawn_thread(
+        entry: u64,
+        stack: u64,
+        argument: u64,
+        exit_address: u64,
+    ) -> Result<usize, SyscallError> {
+        let (leader, tls_block) = {
+            let mut processes = PROCESSES.lock();
+            let leader = processes[Core::local().current_thread].leader;
+            let memory = Process::memory(&mut processes, leader);
+
+            let tls_block = match memory.tls {
+                Some(template) => Some(
+                    tls::create_block(&mut memory.address_space, &template)
+                        .ok_or(SyscallError::ENOMEM)?,
+                ),
+                None => None,
+            };
+
+            (leader, tls_block)
+        };
+
+        let pid = Process::spawn(
+            Some(leader),
+            None,
+            Context {
+                rdi: argument,
+                rsp: stack,
+                rip: entry,
+                fs_base: tls_block.map_or(0, |block| block.thread_pointer),
+                ..Context::default()
+            },
+        );
+
+        let mut processes = PROCESSES.lock();
+        processes[pid].tls_block = tls_block;
+        processes[pid].exit_address = exit_address;
+
+        Ok(pid)
+    }
+
+    /// The pid of the process the running thread belongs to.
+    fn current_pid() -> usize {
+        PROCESSES.lock()[Core::local().current_thread].leader
+    }
+
+    /// The memory of the process that thread `pid` belongs to.
+    fn memory(processes: &mut Slab<Process>, pid: usize) -> &mut Memory {
+        let leader = processes[pid].leader;
+
+        processes[leader]
+            .memory
+            .as_mut()
+            .expect("The first thread of a process holds its memory")
+    }
+
+    /// Adds a thread and queues it to run. It starts a new process unless
+    /// `leader` names the process it belongs to, in which case `memory` must
+    /// be `None`.
+    fn spawn(leader: Option<usize>, memory: Option<Memory>, state: Context) -> usize {
+        let kernel_stack = KernelStack::new();
         let mut processes = PROCESSES.lock();
-        let pid = processes.len();
+        let vacant = processes.vacant_entry();
+        let pid = vacant.key();
 
-        processes.push(Process {
+        vacant.insert(Process {
             pid,
+            leader: leader.unwrap_or(pid),
             fast_entry: true,
             // TODO: When starting new task they should not have zero eleapsed
             // time to pervent from monopolizing the core.
             elapsed: 0,
-            cr3,
-            state: Context {
-                rsp: sp,
-                rip: entry,
-                ..Context::default()
-            },
+            memory,
+            state,
+            kernel_stack,
+            tls_block: None,
+            exit_address: 0,
+            waiting: None,
         });
 
         Core::local().queue.push_back(Task::from(&processes[pid]));
+        pid
     }
 }
 
-static PROCESSES: Spinlock<Vec<Process, VirtualAllocator>> =
-    Spinlock::new(Vec::new_in(VirtualAllocator));
+static PROCESSES: Spinlock<Slab<Process>> = Spinlock::new(Slab::new());
 
 static KERNEL_PAGE_TABLE: OnceCell<u64> = OnceCell::uninit();
 
@@ -360,14 +621,20 @@ fn main(boot_info: &'static mut BootInfo) -> ! {
     initialize(boot_info);
     println!("Welcome to codename annarbor!");
 
-    Process::load(include_bytes!("../../program.elf"), 0x1000_0000);
-    Process::load(include_bytes!("../../program2.elf"), 0x1000_0000);
+    let init = ramdisk::init_path();
+    let elf = ramdisk::find(init).unwrap_or_else(|| panic!("Init program {} not found", init));
+    Process::load(elf, &[init], &[]).unwrap();
 
     unsafe {
         scheduling::switch_process();
     }
 }
 
+/// Returns the page table currently loaded in CR3.
+///
+/// # Safety
+///
+/// The caller must not alias any table it gets to through the result.
 pub unsafe fn get_active_page_table() -> *mut paging::Table {
     let mut cr3: u64;
     unsafe {
@@ -1,14 +1,17 @@
 use core::{
-    alloc::Allocator,
     arch::asm,
     ops::{Index, IndexMut},
-    ptr::null_mut,
 };
 
-use crate::{allocator::allocate_page, KERNEL_PAGE_TABLE, KERNEL_START, PHYSICAL_OFFSET};
+use x86_64::{instructions::tlb, VirtAddr};
+
+use crate::{
+    allocator::{allocate_page, free_page, Owner},
+    KERNEL_PAGE_TABLE, PHYSICAL_OFFSET,
+};
 
 #[repr(transparent)]
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Flags(u64);
 
 impl Flags {
@@ -16,8 +19,43 @@ impl Flags {
     pub const ALL: Self = Self(0b110);
     pub const WRITE: Self = Self(1 << 1);
     pub const USER: Self = Self(1 << 2);
-    pub const HUGE_PAGE: Self = Self(1 << 2);
+    pub const HUGE_PAGE: Self = Self(1 << 7);
     pub const NOT_EXECUTABLE: Self = Self(1 << 63);
+    /// Available to software: the page is shared read-only after a fork and
+    /// is copied on the first write.
+    pub const COPY_ON_WRITE: Self = Self(1 << 9);
+
+    /// The flags that are copied onto intermediate tables. These only ever
+    /// widen access so the final entry alone decides the permissions.
+    const TABLE: Self = Self(Self::WRITE.0 | Self::USER.0);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Flags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::Not for Flags {
+    type Output = Self;
+
+    fn not(self) -> Self::Output {
+        Self(!self.0)
+    }
+}
+
+impl core::ops::BitAnd for Flags {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Self(self.0 & rhs.0)
+    }
 }
 
 #[repr(C)]
@@ -53,15 +91,20 @@ impl Entry {
         Entry(address | flags.0 | 1)
     }
 
-    fn address(&self) -> u64 {
+    pub fn address(&self) -> u64 {
         self.0 & !((1 << 12) - 1) & ((1 << 51) - 1)
     }
 
+    /// The flags of this entry, not including the present bit.
+    pub fn flags(&self) -> Flags {
+        Flags(self.0 & !self.address() & !1)
+    }
+
     pub fn is_present(&self) -> bool {
         (self.0 & 1) == 1
     }
 
-    unsafe fn get_table(&self) -> &mut Table {
+    unsafe fn get_table(&mut self) -> &mut Table {
         unsafe {
             ((self.address() + PHYSICAL_OFFSET) as *mut Table)
                 .as_mut()
@@ -73,139 +116,61 @@ impl Entry {
         self.0 |= flags.0;
     }
 
-    fn is_executable(&self) -> bool {
-        (self.0 >> 63) & 1 == 0
-    }
-}
-
-#[global_allocator]
-static GLOBAL_ALLOCATOR: VirtualAllocator = VirtualAllocator;
-
-unsafe impl core::alloc::GlobalAlloc for VirtualAllocator {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        self.alloc_zeroed(layout)
-    }
-
-    unsafe fn alloc_zeroed(&self, layout: core::alloc::Layout) -> *mut u8 {
-        self.allocate_zeroed(layout)
-            .map(|ptr| ptr.as_ptr() as *mut u8)
-            .unwrap_or(null_mut())
+    pub fn replace_flags(&mut self, flags: Flags) {
+        self.0 = self.address() | flags.0 | 1;
     }
 
-    unsafe fn realloc(
-        &self,
-        _ptr: *mut u8,
-        _layout: core::alloc::Layout,
-        _new_size: usize,
-    ) -> *mut u8 {
-        todo!();
+    /// True if this entry maps a 2 MiB or 1 GiB page rather than pointing to
+    /// another table. Only meaningful for level-3 and level-2 entries.
+    fn is_huge(&self) -> bool {
+        self.is_present() && self.0 & Flags::HUGE_PAGE.0 != 0
     }
+}
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
-        todo!()
-    }
+/// The sizes of page the processor can map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
 }
 
-/// Allocates memory by updating page tables and avoiding memcpys.
-#[derive(Debug)]
-pub struct VirtualAllocator;
-
-unsafe impl core::alloc::Allocator for VirtualAllocator {
-    fn allocate(
-        &self,
-        layout: core::alloc::Layout,
-    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
-        self.allocate_zeroed(layout)
-    }
-
-    fn allocate_zeroed(
-        &self,
-        layout: core::alloc::Layout,
-    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
-        let page_table = unsafe { crate::get_active_page_table().as_mut().unwrap() };
-        let minimum = table_indies(KERNEL_START as usize)[0];
-
-        // Make sure alignment is less than the page size. This is unlikly,
-        // but it's still good to check. This is OK because Layout::align is
-        // guaranteed to be a power of 2.
-        if layout.align() > 4096 {
-            return Err(core::alloc::AllocError);
+impl PageSize {
+    pub const fn bytes(self) -> usize {
+        match self {
+            PageSize::Size4KiB => 1 << 12,
+            PageSize::Size2MiB => 1 << 21,
+            PageSize::Size1GiB => 1 << 30,
         }
+    }
 
-        let address = page_table
-            .0
-            .iter()
-            .enumerate()
-            .skip(minimum)
-            .find_map(|(index, entry)| match entry {
-                _ if !entry.is_present() => Some(index << 39),
-                _ if !entry.is_executable() => todo!(),
-                _ => None,
-            })
-            .unwrap()
-            | KERNEL_START as usize;
-
-        unsafe {
-            page_table.create_mapping(address, allocate_page(1), Flags::WRITE);
-        }
+    pub const fn pages(self) -> u64 {
+        (self.bytes() / 4096) as u64
+    }
 
-        Ok(core::ptr::NonNull::slice_from_raw_parts(
-            core::ptr::NonNull::new(address as *mut u8).unwrap(),
-            4096,
-        ))
-    }
-
-    unsafe fn grow(
-        &self,
-        ptr: core::ptr::NonNull<u8>,
-        old_layout: core::alloc::Layout,
-        new_layout: core::alloc::Layout,
-    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
-        self.grow_zeroed(ptr, old_layout, new_layout)
-    }
-
-    unsafe fn grow_zeroed(
-        &self,
-        ptr: core::ptr::NonNull<u8>,
-        old_layout: core::alloc::Layout,
-        new_layout: core::alloc::Layout,
-    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
-        println!(
-            "growing from {} to {}",
-            old_layout.size(),
-            new_layout.size()
-        );
-        // Make sure alignment is less than the page size. This is unlikly,
-        // but it's still good to check. This is OK because Layout::align is
-        // guaranteed to be a power of 2.
-        if new_layout.align() > 4096 {
-            return Err(core::alloc::AllocError);
+    /// The number of tables walked to reach the entry mapping a page of
+    /// this size, including the level-4 table.
+    const fn depth(self) -> usize {
+        match self {
+            PageSize::Size4KiB => 4,
+            PageSize::Size2MiB => 3,
+            PageSize::Size1GiB => 2,
         }
+    }
 
-        let page_table = unsafe { crate::get_active_page_table().as_mut().unwrap() };
-
-        let indies = table_indies(ptr.as_ptr() as usize);
-        let pages_needed = (new_layout.size() - old_layout.size()).div_ceil(4096);
-
-        for page in 0..pages_needed {
-            let table =
-                page_table[indies[0]].get_table()[indies[1]].get_table()[indies[2]].get_table();
-            let new_page = allocate_page(1);
-            table[indies[3] + page] = Entry::new(new_page as u64, Flags::WRITE);
+    const fn flags(self) -> Flags {
+        match self {
+            PageSize::Size4KiB => Flags::NONE,
+            _ => Flags::HUGE_PAGE,
         }
-
-        Ok(core::ptr::NonNull::slice_from_raw_parts(
-            ptr,
-            (indies[3] + pages_needed) * 4096,
-        ))
     }
+}
 
-    unsafe fn deallocate(&self, _ptr: core::ptr::NonNull<u8>, layout: core::alloc::Layout) {
-        todo!(
-            "Implement deallocation for VirtualAllocator with layout: {:#?}",
-            layout
-        );
-    }
+/// Returns true if the processor can map 1 GiB pages.
+pub fn supports_gigabyte_pages() -> bool {
+    const CPUID_FEAT_EDX_PDPE1GB: u32 = 1 << 26;
+
+    (unsafe { core::arch::x86_64::__cpuid(0x8000_0001) }.edx & CPUID_FEAT_EDX_PDPE1GB) != 0
 }
 
 #[repr(C, align(4096))]
@@ -221,6 +186,41 @@ fn table_indies(address: usize) -> [usize; 4] {
     ]
 }
 
+/// Index of the first level-4 entry that belongs to the kernel. Tables below
+/// it are private to one address space and may be freed once empty, while
+/// the ones above are shared by every process.
+const KERNEL_HALF: usize = 256;
+
+fn flush(virtual_address: usize) {
+    tlb::flush(VirtAddr::new(virtual_address as u64));
+}
+
+/// The tables visited while resolving an address, from the level-4 table
+/// down to the one holding the final entry.
+struct Walk {
+    tables: [*mut Table; 4],
+    indies: [usize; 4],
+    size: PageSize,
+}
+
+/// The pointers stay valid as long as the tables walked are not freed, and
+/// it is up to the caller not to hold two references to the same entry.
+impl Walk {
+    /// The entry at `level` of the walk, where zero is the level-4 table.
+    fn at(&self, level: usize) -> *mut Entry {
+        unsafe { core::ptr::addr_of_mut!((*self.tables[level]).0[self.indies[level]]) }
+    }
+
+    fn entry(&self) -> *mut Entry {
+        self.at(self.size.depth() - 1)
+    }
+
+    /// The entries pointing to the tables below them, top first.
+    fn parents(&self) -> impl Iterator<Item = *mut Entry> + '_ {
+        (0..self.size.depth() - 1).map(|level| self.at(level))
+    }
+}
+
 impl Table {
     pub unsafe fn activate(pointer: *const Table) {
         asm!("mov cr3, {}", in(reg) pointer as u64 - unsafe { PHYSICAL_OFFSET });
@@ -230,26 +230,29 @@ impl Table {
         Self::activate(*KERNEL_PAGE_TABLE.get().unwrap() as *const Table)
     }
 
-    /// Creates a copy of the current table and returns a pointer it.
-    pub fn new_copy() -> *mut Table {
-        let mut cr3: u64;
-        unsafe {
-            asm!("mov {x}, cr3", x = out(reg) cr3);
+    /// Creates a copy of the current table and returns a pointer it, or
+    /// `None` if there is no memory left for it.
+    pub fn new_copy() -> Option<*mut Table> {
+        let page_table = unsafe { &*crate::get_active_page_table() };
+
+        let page = allocate_page(1, Owner::PageTable);
+        if page == 0 {
+            return None;
         }
-        let page_table = unsafe { ((cr3 + PHYSICAL_OFFSET) as *mut Table).as_mut().unwrap() };
 
-        let page = allocate_page(1);
         let table = (page as u64 + unsafe { PHYSICAL_OFFSET }) as *mut Table;
 
         unsafe {
             *table = *page_table;
         }
 
+        // The user half only holds what the bootloader left behind, which
+        // must not end up shared with (and freed by) a process.
         unsafe {
-            (*table).0[0] = Entry::EMPTY;
+            (&mut (*table).0)[..KERNEL_HALF].fill(Entry::EMPTY);
         }
 
-        table
+        Some(table)
     }
 
     pub unsafe fn create_mapping(
@@ -257,82 +260,403 @@ impl Table {
         virtual_address: usize,
         physical_address: usize,
         flags: Flags,
-    ) {
+    ) -> bool {
+        self.create_sized_mapping(virtual_address, physical_address, PageSize::Size4KiB, flags)
+    }
+
+    /// Maps a single page of the given size. Both addresses must be aligned
+    /// to the page size. Returns false if a table could not be allocated,
+    /// in which case nothing is mapped.
+    pub unsafe fn create_sized_mapping(
+        &mut self,
+        virtual_address: usize,
+        physical_address: usize,
+        size: PageSize,
+        flags: Flags,
+    ) -> bool {
         assert!(
-            physical_address & 0xfff == 0,
-            "Physical address must be page-aligned"
+            physical_address & (size.bytes() - 1) == 0,
+            "Physical address must be aligned to the page size"
         );
 
         assert!(
-            virtual_address & 0xfff == 0,
-            "Virtual address must be page-aligned"
+            virtual_address & (size.bytes() - 1) == 0,
+            "Virtual address must be aligned to the page size"
         );
 
         let indies = table_indies(virtual_address);
-        let final_table = unsafe {
-            self.get_or_create(indies[0], flags)
-                .get_table()
-                .get_or_create(indies[1], flags)
-                .get_table()
-                .get_or_create(indies[2], flags)
-                .get_table()
-        };
+        let mut table = self;
+        for &index in &indies[..size.depth() - 1] {
+            let Some(entry) = table.get_or_create(index, flags) else {
+                return false;
+            };
 
-        assert!(!final_table[indies[3]].is_present());
+            table = unsafe { entry.get_table() };
+        }
 
-        final_table[indies[3]] = unsafe { Entry::new(physical_address as u64, flags) };
+        let entry = &mut table.0[indies[size.depth() - 1]];
+        assert!(!entry.is_present());
+
+        *entry = unsafe { Entry::new(physical_address as u64, flags | size.flags()) };
+        true
     }
 
-    // TODO: This code crash on real hardware?
-    pub fn set_recursively(&mut self, flags: Flags) {
-        unsafe {
-            for entry in &mut self.0 {
-                if !entry.is_present() {
+    /// Maps `length` bytes of physical memory using the largest pages that
+    /// fit, overwriting whatever the range was mapped to before. Pieces that
+    /// are too small for a huge page are left untouched, as are the tables
+    /// that used to map the replaced entries.
+    pub unsafe fn remap_huge(
+        &mut self,
+        virtual_address: usize,
+        physical_address: usize,
+        length: usize,
+        flags: Flags,
+    ) {
+        let gigabyte_pages = supports_gigabyte_pages();
+        let mut offset = 0;
+
+        while offset < length {
+            let virtual_address = virtual_address + offset;
+            let physical_address = physical_address + offset;
+            let fits = |size: PageSize| {
+                (virtual_address | physical_address) & (size.bytes() - 1) == 0
+                    && length - offset >= size.bytes()
+            };
+
+            let size = if gigabyte_pages && fits(PageSize::Size1GiB) {
+                PageSize::Size1GiB
+            } else if fits(PageSize::Size2MiB) {
+                PageSize::Size2MiB
+            } else {
+                offset += PageSize::Size4KiB.bytes();
+                continue;
+            };
+
+            // Already covered by a page at least this large.
+            if let Some((_, existing)) = self.entry(virtual_address) {
+                if existing.bytes() >= size.bytes() {
+                    offset += size.bytes();
                     continue;
                 }
+            }
+
+            let indies = table_indies(virtual_address);
+            let mut table = &mut *self;
+            for &index in &indies[..size.depth() - 1] {
+                table = table
+                    .get_or_create(index, flags)
+                    .expect("Out of memory for page tables")
+                    .get_table();
+            }
+
+            table.0[indies[size.depth() - 1]] =
+                Entry::new(physical_address as u64, flags | size.flags());
+            offset += size.bytes();
+        }
+
+        tlb::flush_all();
+    }
+
+    /// Get the table entry at the corresponding index or creates a one the
+    /// given flags if it is not present. Only the write and user flags are
+    /// applied, since an intermediate entry restricts every mapping below it.
+    /// Returns `None` if a new table could not be allocated.
+    pub fn get_or_create(&mut self, index: usize, flags: Flags) -> Option<&mut Entry> {
+        let flags = flags & Flags::TABLE;
+
+        if !self.0[index].is_present() {
+            let page = allocate_page(1, Owner::PageTable);
+            if page == 0 {
+                return None;
+            }
+
+            self.0[index] = unsafe { Entry::new(page as u64, flags) };
+        }
+
+        self.0[index].set_flags(flags);
+
+        Some(&mut self.0[index])
+    }
+
+    /// Returns the table the entry at `index` points to, if it is present
+    /// and not a huge page.
+    unsafe fn next_table(&self, index: usize) -> Option<*mut Table> {
+        if !self.0[index].is_present() || self.0[index].is_huge() {
+            return None;
+        }
+
+        Some((self.0[index].address() + PHYSICAL_OFFSET) as *mut Table)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(|entry| !entry.is_present())
+    }
+
+    /// Walks down to the final entry for `virtual_address`, stopping early
+    /// at huge pages. Returns `None` if one of the tables is missing.
+    fn walk(&mut self, virtual_address: usize) -> Option<Walk> {
+        let indies = table_indies(virtual_address);
+        let mut tables = [self as *mut Table; 4];
+
+        for level in 1..4 {
+            let parent = unsafe { &*tables[level - 1] };
+            let entry = &parent.0[indies[level - 1]];
+
+            if entry.is_huge() {
+                let size = match level {
+                    2 => PageSize::Size1GiB,
+                    3 => PageSize::Size2MiB,
+                    _ => panic!("Huge page in the level-4 table"),
+                };
+
+                return Some(Walk {
+                    tables,
+                    indies,
+                    size,
+                });
+            }
+
+            tables[level] = unsafe { parent.next_table(indies[level - 1])? };
+        }
+
+        Some(Walk {
+            tables,
+            indies,
+            size: PageSize::Size4KiB,
+        })
+    }
+
+    /// Returns the physical address `virtual_address` maps to.
+    pub fn translate(&mut self, virtual_address: usize) -> Option<usize> {
+        let walk = self.walk(virtual_address)?;
+        let entry = unsafe { *walk.entry() };
+
+        entry
+            .is_present()
+            .then(|| entry.address() as usize | (virtual_address & (walk.size.bytes() - 1)))
+    }
+
+    /// Returns the final entry for `virtual_address` and the size of the
+    /// page it maps, if it is mapped.
+    pub fn entry(&mut self, virtual_address: usize) -> Option<(Entry, PageSize)> {
+        let walk = self.walk(virtual_address)?;
+        let entry = unsafe { *walk.entry() };
+
+        entry.is_present().then_some((entry, walk.size))
+    }
 
-                entry.set_flags(flags);
+    /// Returns the number of bytes from `virtual_address` to the end of the
+    /// span covered by the first missing entry on the way down to it, which
+    /// is where the next mapping could start. Returns `None` if it is mapped.
+    pub fn unmapped_span(&mut self, virtual_address: usize) -> Option<usize> {
+        let indies = table_indies(virtual_address);
+        let mut table: *mut Table = self;
+
+        for (level, &index) in indies.iter().enumerate() {
+            let entry = unsafe { (*table).0[index] };
+            let span = 1 << (39 - 9 * level);
+
+            if !entry.is_present() {
+                return Some(span - (virtual_address & (span - 1)));
+            }
+
+            if level == 3 || entry.is_huge() {
+                break;
+            }
+
+            table = unsafe { (*table).next_table(index)? };
+        }
+
+        None
+    }
+
+    /// Replaces the flags of an existing mapping. Returns false if the page
+    /// is not mapped.
+    pub unsafe fn update_flags(&mut self, virtual_address: usize, flags: Flags) -> bool {
+        let Some(walk) = self.walk(virtual_address) else {
+            return false;
+        };
 
-                for entry in &mut entry.get_table().0 {
-                    if !entry.is_present() {
-                        continue;
-                    }
+        let entry = &mut *walk.entry();
+        if !entry.is_present() {
+            return false;
+        }
+        entry.replace_flags(flags | walk.size.flags());
 
-                    entry.set_flags(flags);
+        // Make sure the intermediate tables allow what the new flags ask for.
+        for parent in walk.parents() {
+            (*parent).set_flags(flags & Flags::TABLE);
+        }
 
-                    for entry in &mut entry.get_table().0 {
-                        if !entry.is_present() {
-                            continue;
-                        }
+        flush(virtual_address);
+        true
+    }
 
-                        entry.set_flags(flags);
+    /// Removes the mapping containing `virtual_address` and returns the
+    /// physical page it pointed to, which is left for the caller to free.
+    /// Tables in the user half that become empty are released.
+    pub unsafe fn unmap(&mut self, virtual_address: usize) -> Option<(usize, PageSize)> {
+        let walk = self.walk(virtual_address)?;
+        let entry = &mut *walk.entry();
+        if !entry.is_present() {
+            return None;
+        }
 
-                        for entry in &mut entry.get_table().0 {
-                            if !entry.is_present() {
-                                continue;
-                            }
+        let physical_address = entry.address() as usize;
+        *entry = Entry::EMPTY;
+        flush(virtual_address);
 
-                            entry.set_flags(flags);
-                        }
-                    }
+        if walk.indies[0] < KERNEL_HALF {
+            for level in (1..walk.size.depth()).rev() {
+                if !(*walk.tables[level]).is_empty() {
+                    break;
                 }
+
+                let parent = &mut *walk.at(level - 1);
+                free_page(parent.address(), 1);
+                *parent = Entry::EMPTY;
             }
         }
+
+        Some((physical_address, walk.size))
     }
 
-    /// Get the table entry at the corresponding index or creates a one the
-    /// given flags if it is not present.
-    pub fn get_or_create(&mut self, index: usize, flags: Flags) -> &Entry {
-        if !self.0[index].is_present() {
-            let page = allocate_page(1);
-            self.0[index] = unsafe { Entry::new(page as u64, flags) };
+    /// Unmaps every page in `pages` consecutive 4 KiB pages starting at
+    /// `virtual_address`, calling `release` with the physical address and
+    /// size of each page that was mapped. Huge pages must lie entirely
+    /// inside the range.
+    pub unsafe fn unmap_range(
+        &mut self,
+        virtual_address: usize,
+        pages: usize,
+        mut release: impl FnMut(usize, PageSize),
+    ) {
+        let end = virtual_address + pages * 4096;
+        let mut address = virtual_address;
+
+        while address < end {
+            // Skip over missing tables rather than walking every page they
+            // would have mapped.
+            let Some((_, size)) = self.entry(address) else {
+                address += self.unmapped_span(address).unwrap();
+                continue;
+            };
+
+            assert!(
+                address & (size.bytes() - 1) == 0 && address + size.bytes() <= end,
+                "Cannot unmap part of a huge page"
+            );
+
+            if let Some((physical_address, size)) = self.unmap(address) {
+                release(physical_address, size);
+            }
+
+            address += size.bytes();
         }
+    }
 
-        self.0[index].set_flags(flags);
+    /// Points an existing mapping at a different physical page of the same
+    /// size and replaces its flags. Returns false if the page is not mapped.
+    pub unsafe fn replace_mapping(
+        &mut self,
+        virtual_address: usize,
+        physical_address: usize,
+        flags: Flags,
+    ) -> bool {
+        let Some(walk) = self.walk(virtual_address) else {
+            return false;
+        };
 
-        assert!(self.0[index].is_present());
+        let entry = &mut *walk.entry();
+        if !entry.is_present() {
+            return false;
+        }
+
+        *entry = Entry::new(physical_address as u64, flags | walk.size.flags());
+
+        // A forked address space gets read-only tables for copy-on-write
+        // pages, which must allow the write once the page is copied.
+        for parent in walk.parents() {
+            (*parent).set_flags(flags & Flags::TABLE);
+        }
+
+        flush(virtual_address);
+        true
+    }
+
+    /// Calls `visit` with the virtual address, final entry and page size of
+    /// every page mapped in the user half. Changes made to the entries are
+    /// not flushed from the TLB.
+    pub fn for_each_user_page(&mut self, mut visit: impl FnMut(usize, &mut Entry, PageSize)) {
+        for (index, entry) in self.0[..KERNEL_HALF].iter_mut().enumerate() {
+            unsafe { Self::visit_entry(entry, 1, index << 39, &mut visit) };
+        }
+    }
+
+    unsafe fn visit_entry(
+        entry: &mut Entry,
+        level: usize,
+        virtual_address: usize,
+        visit: &mut impl FnMut(usize, &mut Entry, PageSize),
+    ) {
+        if !entry.is_present() {
+            return;
+        }
+
+        let size = match level {
+            2 => PageSize::Size1GiB,
+            3 => PageSize::Size2MiB,
+            _ => PageSize::Size4KiB,
+        };
+
+        if level == 4 || entry.is_huge() {
+            visit(virtual_address, entry, size);
+            return;
+        }
+
+        let shift = 39 - 9 * level;
+        for (index, child) in entry.get_table().0.iter_mut().enumerate() {
+            Self::visit_entry(child, level + 1, virtual_address | index << shift, visit);
+        }
+    }
+
+    /// Releases every page mapped in the user half along with the tables
+    /// mapping them, calling `release` for each mapped page. No TLB entries
+    /// are invalidated, so the table must not be active.
+    pub unsafe fn clear_user_half(&mut self, mut release: impl FnMut(usize, PageSize)) {
+        for entry in &mut self.0[..KERNEL_HALF] {
+            Self::clear_entry(entry, 1, &mut release);
+        }
+    }
+
+    /// Clears an entry of the table at `level`, where the level-4 table is
+    /// level one, first releasing everything below it.
+    unsafe fn clear_entry(
+        entry: &mut Entry,
+        level: usize,
+        release: &mut impl FnMut(usize, PageSize),
+    ) {
+        if !entry.is_present() {
+            return;
+        }
+
+        let size = match level {
+            2 => PageSize::Size1GiB,
+            3 => PageSize::Size2MiB,
+            _ => PageSize::Size4KiB,
+        };
+
+        if level == 4 || entry.is_huge() {
+            release(entry.address() as usize, size);
+        } else {
+            for child in &mut entry.get_table().0 {
+                Self::clear_entry(child, level + 1, release);
+            }
+
+            free_page(entry.address(), 1);
+        }
 
-        return &self.0[index];
+        *entry = Entry::EMPTY;
     }
 }
 
@@ -0,0 +1,94 @@
+use bootloader_api::BootInfo;
+use conquer_once::spin::OnceCell;
+
+/// The ramdisk passed by the bootloader, a ustar archive built by the
+/// build script.
+static ARCHIVE: OnceCell<&'static [u8]> = OnceCell::uninit();
+
+const BLOCK_SIZE: usize = 512;
+
+/// The file naming the path of the program to start at boot.
+const INIT_PATH: &str = "/etc/init";
+
+/// Records where the bootloader loaded the ramdisk. Without one, the archive
+/// is empty.
+pub fn initialize(boot_info: &BootInfo) {
+    ARCHIVE.init_once(|| match boot_info.ramdisk_addr.into_option() {
+        Some(address) => unsafe {
+            core::slice::from_raw_parts(address as *const u8, boot_info.ramdisk_len as usize)
+        },
+        None => &[],
+    });
+}
+
+/// Reads a null-terminated field of a header.
+fn field(header: &[u8]) -> &[u8] {
+    let end = header
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(header.len());
+    &header[..end]
+}
+
+/// Parses an octal number field, as used for sizes.
+fn octal(header: &[u8]) -> Option<usize> {
+    let digits = core::str::from_utf8(field(header)).ok()?.trim();
+    usize::from_str_radix(digits, 8).ok()
+}
+
+/// Iterates over the regular files in the archive, as paths without the
+/// leading slash and their contents. Each file starts on a 512-byte
+/// boundary, so contents are suitably aligned for the ELF loader.
+pub fn files() -> impl Iterator<Item = (&'static str, &'static [u8])> {
+    let archive: &'static [u8] = ARCHIVE.get().expect("The ramdisk is not initialized");
+    let mut offset = 0;
+
+    core::iter::from_fn(move || loop {
+        let header = archive.get(offset..offset + BLOCK_SIZE)?;
+
+        // The archive ends with zeroed blocks.
+        if header[0] == 0 {
+            return None;
+        }
+
+        let size = octal(&header[124..136])?;
+        let contents = archive.get(offset + BLOCK_SIZE..offset + BLOCK_SIZE + size)?;
+        offset += BLOCK_SIZE + size.next_multiple_of(BLOCK_SIZE);
+
+        // Only regular files, skipping directories, links and the like.
+        if !matches!(header[156], b'0' | 0) {
+            continue;
+        }
+
+        // Long paths are split between the name and the ustar prefix.
+        let name = core::str::from_utf8(field(&header[..100])).ok()?;
+        let prefix = core::str::from_utf8(field(&header[345..500])).ok()?;
+        if !prefix.is_empty() {
+            println!(
+                "Skipping {}/{}: prefixed paths are not supported",
+                prefix, name
+            );
+            continue;
+        }
+
+        return Some((name.trim_start_matches("./"), contents));
+    })
+}
+
+/// Returns the contents of the file at absolute `path`.
+pub fn find(path: &str) -> Option<&'static [u8]> {
+    let path = path.strip_prefix('/')?;
+
+    files()
+        .find(|&(name, _)| name == path)
+        .map(|(_, contents)| contents)
+}
+
+/// Returns the path of the first program to run, as named in `/etc/init`.
+pub fn init_path() -> &'static str {
+    let contents = find(INIT_PATH).expect("The ramdisk has no /etc/init");
+
+    core::str::from_utf8(contents)
+        .expect("/etc/init is not valid UTF-8")
+        .trim()
+}
@@ -0,0 +1,33 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::random::RdRand;
+
+/// The state of the fallback generator, used when RDRAND is missing or keeps
+/// failing.
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Returns 64 random bits from RDRAND, or from a generator stirred with the
+/// time stamp counter if that is not available.
+pub fn random_u64() -> u64 {
+    if let Some(value) = RdRand::new().and_then(|rdrand| (0..10).find_map(|_| rdrand.get_u64())) {
+        return value;
+    }
+
+    // SplitMix64, with the time stamp counter added into every step.
+    let time = unsafe { core::arch::x86_64::_rdtsc() };
+    let mut value = STATE
+        .fetch_add(0x9e37_79b9_7f4a_7c15 ^ time, Ordering::Relaxed)
+        .wrapping_add(time);
+
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+/// Returns a random multiple of `alignment` in `start..end`. Both ends must
+/// be multiples of `alignment`.
+pub fn random_address(start: usize, end: usize, alignment: usize) -> usize {
+    let slots = (end - start) / alignment;
+
+    start + (random_u64() as usize % slots) * alignment
+}
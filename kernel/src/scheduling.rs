@@ -1,6 +1,21 @@
 use core::arch::asm;
 
-use crate::{Context, Core, PROCESSES};
+use x86_64::{
+    registers::model_specific::{FsBase, GsBase, KernelGsBase},
+    VirtAddr,
+};
+
+use alloc::vec::Vec;
+
+use crate::{
+    futex, hlt_loop, shared_memory, syscall::SystemCallFrame, Context, Core, Process, PROCESSES,
+};
+
+const EXIT_STACK_SIZE: usize = 4096 * 4;
+
+/// The stack a process is torn down on, since its own stack goes away with
+/// its address space.
+static mut EXIT_STACK: [u8; EXIT_STACK_SIZE] = [0; EXIT_STACK_SIZE];
 
 pub unsafe extern "C" fn current_context_address() -> *mut Context {
     crate::paging::Table::activate_kernel_table();
@@ -18,6 +33,12 @@ pub unsafe extern "C" fn switch_to_userspace_slow(context: *mut Context, cr3: u6
         "push rbx",
         "push (4 * 8) | 3",
         "push [rax + 0x50]",
+        "mov rbx, [rax + 0x60]",
+        "mov rbp, [rax + 0x68]",
+        "mov r12, [rax + 0x70]",
+        "mov r13, [rax + 0x78]",
+        "mov r14, [rax + 0x80]",
+        "mov r15, [rax + 0x88]",
         "mov rdi, [rax + 0x08]",
         "push [rax + 0x10]",
         "mov rdx, [rax + 0x18]",
@@ -38,14 +59,26 @@ pub unsafe extern "C" fn switch_process() -> ! {
     let core = Core::local();
     let (context, cr3) = {
         let mut processes = PROCESSES.lock();
-        let next_process = pick_next(core);
+        let Some(next_process) = pick_next(core) else {
+            println!("No processes left to run.");
+            hlt_loop();
+        };
 
         core.current_thread = next_process;
+        core.kernel_stack = processes[next_process].kernel_stack.top();
+        FsBase::write(VirtAddr::new(processes[next_process].state.fs_base));
+
+        // Set both GS bases, as a process that exited in a system call never
+        // swapped them back.
+        GsBase::write(VirtAddr::zero());
+        KernelGsBase::write(VirtAddr::from_ptr(core));
 
         crate::apic::end_of_interrupt();
         (
             &mut processes[next_process].state as *mut Context,
-            processes[next_process].cr3,
+            Process::memory(&mut processes, next_process)
+                .address_space
+                .cr3(),
         )
     };
 
@@ -64,8 +97,149 @@ pub unsafe extern "C" fn requeue_active_process() {
     core.queue.push_back(task);
 }
 
-fn pick_next(core: &mut Core) -> usize {
-    core.queue.pop_front().unwrap().1
+fn pick_next(core: &mut Core) -> Option<usize> {
+    core.queue.pop_front().map(|task| task.1)
+}
+
+/// Stops running the current thread, which resumes by returning `result`
+/// from the system call it made with `frame`. It is queued to run again,
+/// unless it is `waiting` on a futex, in which case it runs once woken.
+pub unsafe fn suspend_current_thread(
+    frame: &SystemCallFrame,
+    result: u64,
+    waiting: Option<u64>,
+) -> ! {
+    {
+        let mut processes = PROCESSES.lock();
+        let thread = &mut processes[Core::local().current_thread];
+
+        thread.state = Context::after_system_call(frame, result, thread.state.fs_base);
+        thread.waiting = waiting;
+    }
+
+    if waiting.is_none() {
+        requeue_active_process();
+    }
+
+    switch_process()
+}
+
+/// Ends the process of the running thread, with all of its threads, and
+/// switches to the next thread. Moves onto a kernel stack first so that the
+/// process's memory can be freed.
+#[naked]
+pub unsafe extern "C" fn exit_current_process(code: u64) -> ! {
+    asm!(
+        "lea rsp, [rip + {stack} + {size}]",
+        "and rsp, -16",
+        "call {finish}",
+        stack = sym EXIT_STACK,
+        size = const EXIT_STACK_SIZE,
+        finish = sym finish_exit,
+        options(noreturn)
+    )
+}
+
+/// Ends the running thread, like `exit_current_process` does the process.
+/// The first thread of a process lives as long as the process, so ending it
+/// ends the process with an exit code of zero.
+#[naked]
+pub unsafe extern "C" fn exit_current_thread() -> ! {
+    asm!(
+        "lea rsp, [rip + {stack} + {size}]",
+        "and rsp, -16",
+        "call {finish}",
+        stack = sym EXIT_STACK,
+        size = const EXIT_STACK_SIZE,
+        finish = sym finish_thread_exit,
+        options(noreturn)
+    )
+}
+
+/// Exit code given to processes killed by the kernel.
+pub const KILLED: u64 = u64::MAX;
+
+/// Ends the running process after a fatal error, such as a page fault that
+/// could not be resolved.
+pub unsafe fn kill_current_process(reason: core::fmt::Arguments) -> ! {
+    println!(
+        "Killing process {}: {}",
+        Core::local().current_thread,
+        reason
+    );
+
+    exit_current_process(KILLED)
+}
+
+unsafe extern "C" fn finish_exit(code: u64) -> ! {
+    crate::paging::Table::activate_kernel_table();
+
+    let core = Core::local();
+    let (pid, threads) = {
+        let mut processes = PROCESSES.lock();
+        let pid = processes[core.current_thread].leader;
+
+        let threads: Vec<usize> = processes
+            .iter()
+            .filter(|(_, thread)| thread.leader == pid)
+            .map(|(tid, _)| tid)
+            .collect();
+        let threads: Vec<Process> = threads
+            .into_iter()
+            .map(|tid| processes.remove(tid))
+            .collect();
+
+        // Pids are reused, so the queued threads and the grants go before
+        // the lock is released.
+        core.queue.retain(|task| processes.contains(task.1));
+        shared_memory::revoke(pid);
+
+        (pid, threads)
+    };
+    println!("Process {} exited with code: {}!", pid, code);
+
+    // Dropping the first thread releases the address space.
+    drop(threads);
+
+    switch_process()
+}
+
+unsafe extern "C" fn finish_thread_exit() -> ! {
+    crate::paging::Table::activate_kernel_table();
+
+    let thread = {
+        let mut processes = PROCESSES.lock();
+        let tid = Core::local().current_thread;
+        let pid = processes[tid].leader;
+
+        if tid == pid {
+            drop(processes);
+            finish_exit(0);
+        }
+
+        let thread = processes.remove(tid);
+        let memory = Process::memory(&mut processes, pid);
+
+        if let Some(block) = thread.tls_block {
+            memory.address_space.unmap(block.start, block.length);
+        }
+
+        // Tell whoever joins the thread that it is gone.
+        if thread.exit_address != 0
+            && memory
+                .address_space
+                .write(thread.exit_address as usize, &0u32.to_le_bytes())
+        {
+            futex::wake(&mut processes, pid, thread.exit_address, usize::MAX);
+        }
+
+        thread
+    };
+
+    // Its kernel stack goes too, which is why this runs on the exit stack.
+    drop(thread);
+
+    switch_process()
 }
 
 #[naked]
@@ -83,6 +257,13 @@ pub unsafe extern "C" fn timer_interrupt_handler() -> ! {
 
         "call {current_context_address}",
 
+        "mov [rax + 0x60], rbx",
+        "mov [rax + 0x68], rbp",
+        "mov [rax + 0x70], r12",
+        "mov [rax + 0x78], r13",
+        "mov [rax + 0x80], r14",
+        "mov [rax + 0x88], r15",
+
         "pop rdi",
         "mov [rax], rdi",
 
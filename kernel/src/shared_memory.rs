@@ -0,0 +1,113 @@
+use alloc::{vec, vec::Vec};
+
+use slab::Slab;
+use spinning_top::Spinlock;
+
+use crate::allocator::{allocate_page, free_page, statistics, Owner};
+
+/// Physical memory that can be mapped into several address spaces at once.
+struct SharedMemory {
+    /// The pages of the object. The object holds a reference to each of
+    /// them, and so does every page table entry mapping them.
+    frames: Vec<usize>,
+    /// The processes allowed to map the object.
+    granted: Vec<usize>,
+    /// The number of regions the object is mapped in. The object is released
+    /// once this drops back to zero.
+    mappings: usize,
+}
+
+static OBJECTS: Spinlock<Slab<SharedMemory>> = Spinlock::new(Slab::new());
+
+/// Creates an object of `pages` zeroed pages that only `owner` may map.
+/// Returns its id, or `None` if memory ran out. The object must be mapped
+/// straight away, as it is only released by its last mapping going away.
+pub fn create(pages: usize, owner: usize) -> Option<usize> {
+    if pages == 0 || pages as u64 > statistics().free {
+        return None;
+    }
+
+    let mut frames = Vec::with_capacity(pages);
+
+    for _ in 0..pages {
+        match allocate_page(1, Owner::User) {
+            0 => {
+                for frame in frames {
+                    unsafe { free_page(frame as u64, 1) };
+                }
+
+                return None;
+            }
+            frame => frames.push(frame),
+        }
+    }
+
+    Some(OBJECTS.lock().insert(SharedMemory {
+        frames,
+        granted: vec![owner],
+        mappings: 0,
+    }))
+}
+
+/// Allows `to` to map object `id`, if `from` is allowed to.
+pub fn grant(id: usize, from: usize, to: usize) -> bool {
+    let mut objects = OBJECTS.lock();
+    let Some(object) = objects.get_mut(id) else {
+        return false;
+    };
+
+    if !object.granted.contains(&from) {
+        return false;
+    }
+
+    if !object.granted.contains(&to) {
+        object.granted.push(to);
+    }
+
+    true
+}
+
+/// Takes away every grant of `pid`, once it has exited and before its pid
+/// can be reused by another process.
+pub fn revoke(pid: usize) {
+    for (_, object) in OBJECTS.lock().iter_mut() {
+        object.granted.retain(|&granted| granted != pid);
+    }
+}
+
+/// Counts a new mapping of object `id` by `pid` and returns its pages, or
+/// `None` if `pid` is not allowed to map it.
+pub fn map(id: usize, pid: usize) -> Option<Vec<usize>> {
+    let mut objects = OBJECTS.lock();
+    let object = objects.get_mut(id)?;
+
+    if !object.granted.contains(&pid) {
+        return None;
+    }
+
+    object.mappings += 1;
+    Some(object.frames.clone())
+}
+
+/// Counts a region that was split off or copied from an existing mapping.
+pub fn add_mapping(id: usize) {
+    OBJECTS.lock()[id].mappings += 1;
+}
+
+/// Drops a mapping of object `id`, releasing the object when it was the last
+/// one. Its pages are freed once they are no longer mapped anywhere.
+pub fn remove_mapping(id: usize) {
+    let mut objects = OBJECTS.lock();
+
+    objects[id].mappings -= 1;
+    if objects[id].mappings > 0 {
+        return;
+    }
+
+    let object = objects.remove(id);
+    drop(objects);
+
+    for frame in object.frames {
+        unsafe { free_page(frame as u64, 1) };
+    }
+}
@@ -0,0 +1,383 @@
+use core::arch::asm;
+
+use crate::{
+    address_space::{self, RegionKind},
+    allocator,
+    elf::ELFError,
+    futex, ramdisk, scheduling, shared_memory, time,
+    user_memory::UserSlice,
+    Core, Memory, Process, PROCESSES, USER_END,
+};
+
+/// The most bytes printed by one `write`.
+const MAX_WRITE: u64 = 64 * 1024;
+
+/// The longest path accepted by `spawn`.
+const MAX_PATH: u64 = 4096;
+
+/// Why a system call failed. It is returned to user space negated in `rax`,
+/// with the numbers Linux uses.
+#[repr(i64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    /// The process is not allowed to do this.
+    EPERM = 1,
+    /// No such file.
+    ENOENT = 2,
+    /// No such process.
+    ESRCH = 3,
+    /// The file is not an executable the kernel can load.
+    ENOEXEC = 8,
+    /// The futex no longer holds the expected value.
+    EAGAIN = 11,
+    /// Out of memory or address space, or the range is not mapped.
+    ENOMEM = 12,
+    /// A pointer argument does not point to memory the process can access.
+    EFAULT = 14,
+    EINVAL = 22,
+    /// There is no system call with this number.
+    ENOSYS = 38,
+}
+
+use SyscallError::*;
+
+type SyscallResult = Result<u64, SyscallError>;
+
+/// The system calls, indexed by number.
+static SYSTEM_CALLS: &[fn(&SystemCallFrame) -> SyscallResult] = &[
+    exit,         // 0
+    write,        // 1
+    meminfo,      // 2
+    fork,         // 3
+    mmap,         // 4
+    munmap,       // 5
+    mprotect,     // 6
+    brk,          // 7
+    shm_create,   // 8
+    shm_grant,    // 9
+    shm_map,      // 10
+    shm_unmap,    // 11
+    spawn,        // 12
+    clock,        // 13
+    spawn_thread, // 14
+    exit_thread,  // 15
+    futex_wait,   // 16
+    futex_wake,   // 17
+    yield_now,    // 18
+];
+
+/// The user registers saved by `dispatch_system_call` on the kernel stack,
+/// in the order they are found there.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SystemCallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+impl SystemCallFrame {
+    /// The arguments of the system call, in the order of the System V
+    /// calling convention with `r10` in place of `rcx`.
+    fn arguments(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+fn protection_flags(protection: u64) -> Result<crate::paging::Flags, SyscallError> {
+    address_space::protection_flags(protection).ok_or(EINVAL)
+}
+
+fn exit(frame: &SystemCallFrame) -> SyscallResult {
+    unsafe { scheduling::exit_current_process(frame.rdi) }
+}
+
+/// Prints UTF-8 text, returning how many bytes were printed. Long messages
+/// are cut short, at a character boundary.
+fn write(frame: &SystemCallFrame) -> SyscallResult {
+    let [message, length, ..] = frame.arguments();
+    let bytes = UserSlice::new(message, length.min(MAX_WRITE))?.read()?;
+
+    let text = match core::str::from_utf8(&bytes) {
+        Ok(text) => text,
+        // A character split by the cut is left for the next call.
+        Err(error) if error.error_len().is_none() && error.valid_up_to() > 0 => unsafe {
+            core::str::from_utf8_unchecked(&bytes[..error.valid_up_to()])
+        },
+        Err(_) => return Err(EINVAL),
+    };
+
+    print!("{}", text);
+    Ok(text.len() as u64)
+}
+
+fn meminfo(frame: &SystemCallFrame) -> SyscallResult {
+    let size = core::mem::size_of::<allocator::MemoryStatistics>();
+    let statistics = allocator::statistics();
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            &statistics as *const allocator::MemoryStatistics as *const u8,
+            size,
+        )
+    };
+
+    UserSlice::new(frame.rdi, size as u64)?.write(bytes)?;
+    Ok(0)
+}
+
+fn fork(frame: &SystemCallFrame) -> SyscallResult {
+    Process::fork(frame).map(|pid| pid as u64)
+}
+
+fn mmap(frame: &SystemCallFrame) -> SyscallResult {
+    let [address, length, protection, ..] = frame.arguments();
+    let flags = protection_flags(protection)?;
+
+    Memory::current(&mut PROCESSES.lock())
+        .address_space
+        .map_anonymous(address as usize, length as usize, flags)
+        .map(|address| address as u64)
+        .ok_or(ENOMEM)
+}
+
+fn munmap(frame: &SystemCallFrame) -> SyscallResult {
+    let [address, length, ..] = frame.arguments();
+
+    let unmapped = Memory::current(&mut PROCESSES.lock())
+        .address_space
+        .unmap(address as usize, length as usize);
+
+    unmapped.then_some(0).ok_or(EINVAL)
+}
+
+fn mprotect(frame: &SystemCallFrame) -> SyscallResult {
+    let [address, length, protection, ..] = frame.arguments();
+    let flags = protection_flags(protection)?;
+
+    let protected = Memory::current(&mut PROCESSES.lock())
+        .address_space
+        .protect(address as usize, length as usize, flags);
+
+    protected.then_some(0).ok_or(ENOMEM)
+}
+
+/// Moves the program break, and returns where it ends up.
+fn brk(frame: &SystemCallFrame) -> SyscallResult {
+    Ok(Memory::current(&mut PROCESSES.lock()).set_break(frame.rdi as usize) as u64)
+}
+
+/// Creates a shared memory object and maps it, returning its address.
+fn shm_create(frame: &SystemCallFrame) -> SyscallResult {
+    let [size, protection, ..] = frame.arguments();
+    let flags = protection_flags(protection)?;
+
+    let pid = Process::current_pid();
+    let id = shared_memory::create((size as usize).div_ceil(4096), pid).ok_or(ENOMEM)?;
+
+    Memory::current(&mut PROCESSES.lock())
+        .address_space
+        .map_shared(id, pid, flags)
+        .map(|address| address as u64)
+        .ok_or(ENOMEM)
+}
+
+/// Allows a process to map the shared memory object at an address, returning
+/// the id of the object.
+fn shm_grant(frame: &SystemCallFrame) -> SyscallResult {
+    let [address, to, ..] = frame.arguments();
+    let mut processes = PROCESSES.lock();
+    let pid = processes[Core::local().current_thread].leader;
+
+    let Some(RegionKind::Shared(id)) = Memory::current(&mut processes)
+        .address_space
+        .find_region(address as usize)
+        .map(|region| region.kind)
+    else {
+        return Err(EINVAL);
+    };
+
+    // Only the pid of a process, rather than one of its other threads.
+    if processes
+        .get(to as usize)
+        .is_none_or(|process| process.leader != process.pid)
+    {
+        return Err(ESRCH);
+    }
+
+    if !shared_memory::grant(id, pid, to as usize) {
+        return Err(EPERM);
+    }
+
+    Ok(id as u64)
+}
+
+fn shm_map(frame: &SystemCallFrame) -> SyscallResult {
+    let [id, protection, ..] = frame.arguments();
+    let flags = protection_flags(protection)?;
+
+    let pid = Process::current_pid();
+    Memory::current(&mut PROCESSES.lock())
+        .address_space
+        .map_shared(id as usize, pid, flags)
+        .map(|address| address as u64)
+        .ok_or(EPERM)
+}
+
+fn shm_unmap(frame: &SystemCallFrame) -> SyscallResult {
+    let mut processes = PROCESSES.lock();
+    let address_space = &mut Memory::current(&mut processes).address_space;
+
+    let Some(&region) = address_space.find_region(frame.rdi as usize) else {
+        return Err(EINVAL);
+    };
+
+    if !matches!(region.kind, RegionKind::Shared(_))
+        || !address_space.unmap(region.start, region.end - region.start)
+    {
+        return Err(EINVAL);
+    }
+
+    Ok(0)
+}
+
+/// Starts a program from the ramdisk, returning its pid.
+fn spawn(frame: &SystemCallFrame) -> SyscallResult {
+    let [path, length, ..] = frame.arguments();
+    if length > MAX_PATH {
+        return Err(EINVAL);
+    }
+
+    let path = UserSlice::new(path, length)?.read()?;
+    let path = core::str::from_utf8(&path).map_err(|_| EINVAL)?;
+
+    let elf = ramdisk::find(path).ok_or(ENOENT)?;
+
+    match Process::load(elf, &[path], &[]) {
+        Ok(pid) => Ok(pid as u64),
+        Err(ELFError::OutOfMemory | ELFError::NoRoom) => Err(ENOMEM),
+        Err(_) => Err(ENOEXEC),
+    }
+}
+
+/// Returns the nanoseconds since boot.
+fn clock(_frame: &SystemCallFrame) -> SyscallResult {
+    Ok(time::nanoseconds())
+}
+
+/// Starts a thread in the running process that calls `entry(argument)` on
+/// `stack`, returning its pid. When it exits, the `u32` at `exit_address` is
+/// zeroed and woken as a futex, unless the address is zero.
+fn spawn_thread(frame: &SystemCallFrame) -> SyscallResult {
+    let [entry, stack, argument, exit_address, ..] = frame.arguments();
+
+    // Returning to a non-canonical address would fault in the kernel.
+    if entry >= USER_END as u64 || stack >= USER_END as u64 {
+        return Err(EFAULT);
+    }
+
+    Process::spawn_thread(entry, stack, argument, exit_address).map(|pid| pid as u64)
+}
+
+fn exit_thread(_frame: &SystemCallFrame) -> SyscallResult {
+    unsafe { scheduling::exit_current_thread() }
+}
+
+fn futex_wait(frame: &SystemCallFrame) -> SyscallResult {
+    let [address, expected, ..] = frame.arguments();
+    futex::wait(frame, address, expected as u32)
+}
+
+/// Wakes up to `count` threads waiting on a futex, returning how many.
+fn futex_wake(frame: &SystemCallFrame) -> SyscallResult {
+    let [address, count, ..] = frame.arguments();
+    let mut processes = PROCESSES.lock();
+    let pid = processes[Core::local().current_thread].leader;
+
+    Ok(futex::wake(&mut processes, pid, address, count as usize) as u64)
+}
+
+/// Lets the other threads run before returning.
+fn yield_now(frame: &SystemCallFrame) -> SyscallResult {
+    unsafe { scheduling::suspend_current_thread(frame, 0, None) }
+}
+
+unsafe extern "C" fn system_call_handler(code: u64, frame: &SystemCallFrame) -> u64 {
+    let result = match SYSTEM_CALLS.get(code as usize) {
+        Some(system_call) => system_call(frame),
+        None => Err(ENOSYS),
+    };
+
+    match result {
+        Ok(value) => value,
+        Err(error) => -(error as i64) as u64,
+    }
+}
+
+/// The entry point of the `syscall` instruction. Switches to the kernel
+/// stack of the running thread, found through the `Core` in the kernel GS
+/// base, and back to the user stack before returning.
+#[naked]
+pub unsafe extern "C" fn dispatch_system_call() -> ! {
+    asm!(
+        "swapgs",
+        "mov gs:[{user_stack}], rsp",
+        "mov rsp, gs:[{kernel_stack}]",
+        // Save every user register but rax, building a `SystemCallFrame`.
+        "push qword ptr gs:[{user_stack}]",
+        "push rcx",
+        "push r11",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // The system call code and the frame are the arguments.
+        "mov rdi, rax",
+        "mov rsi, rsp",
+        // The kernel stack starts 16-byte aligned and the frame is 15
+        // quadwords, so pad it to keep the stack aligned at the call.
+        "sub rsp, 8",
+        "call {handler}",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop r11",
+        "pop rcx",
+        "pop rsp",
+        "swapgs",
+        "sysretq",
+        user_stack = const core::mem::offset_of!(Core, user_stack),
+        kernel_stack = const core::mem::offset_of!(Core, kernel_stack),
+        handler = sym system_call_handler,
+        options(noreturn)
+    )
+}
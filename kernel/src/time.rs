@@ -0,0 +1,52 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::port::Port;
+
+/// The input frequency of the programmable interval timer, in hertz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// How long the time stamp counter is measured against the PIT for.
+const CALIBRATION_MILLISECONDS: u64 = 10;
+
+/// Time stamp counter ticks per second.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// The time stamp counter at boot.
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Measures the frequency of the time stamp counter, by counting its ticks
+/// while PIT channel 2 counts down. Must run with interrupts disabled.
+pub fn initialize() {
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel = Port::<u8>::new(0x42);
+    let count = PIT_FREQUENCY * CALIBRATION_MILLISECONDS / 1000;
+
+    let ticks = unsafe {
+        // Enable the channel 2 gate, with the speaker off.
+        let value = gate.read();
+        gate.write((value & !0x02) | 0x01);
+
+        // Channel 2, low then high byte, interrupt on terminal count.
+        command.write(0b1011_0000);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+
+        let start = rdtsc();
+        while gate.read() & 0x20 == 0 {}
+        rdtsc() - start
+    };
+
+    TSC_FREQUENCY.store(ticks * 1000 / CALIBRATION_MILLISECONDS, Ordering::Relaxed);
+    TSC_START.store(rdtsc(), Ordering::Relaxed);
+}
+
+/// Nanoseconds since boot.
+pub fn nanoseconds() -> u64 {
+    let ticks = rdtsc() - TSC_START.load(Ordering::Relaxed);
+
+    (ticks as u128 * 1_000_000_000 / TSC_FREQUENCY.load(Ordering::Relaxed) as u128) as u64
+}
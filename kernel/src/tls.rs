@@ -0,0 +1,42 @@
+use crate::{
+    address_space::{self, AddressSpace, PROT_READ, PROT_WRITE},
+    elf::TlsTemplate,
+};
+
+/// A thread's TLS block, as mapped by `create_block`.
+#[derive(Debug, Clone, Copy)]
+pub struct Block {
+    pub start: usize,
+    pub length: usize,
+    /// The address loaded into the FS base.
+    pub thread_pointer: u64,
+}
+
+/// Allocates a thread's TLS block and fills it from `template`. The block
+/// uses the x86-64 layout: the data ends right below the thread pointer,
+/// which points at a word holding its own address. Returns `None` if there
+/// is no room for the block.
+pub fn create_block(address_space: &mut AddressSpace, template: &TlsTemplate) -> Option<Block> {
+    let align = template.align.max(8);
+    let data_size = template.size.checked_next_multiple_of(align)?;
+
+    // Leave room to align the thread pointer, which the start of the data
+    // is aligned with.
+    let length = data_size.checked_add(align)?.checked_add(8)?;
+    let flags = address_space::protection_flags(PROT_READ | PROT_WRITE).unwrap();
+    let start = address_space.map_anonymous(0, length, flags)?;
+
+    let thread_pointer = (start + data_size).next_multiple_of(align);
+
+    assert!(
+        address_space.write(thread_pointer - data_size, template.data)
+            && address_space.write(thread_pointer, &(thread_pointer as u64).to_le_bytes()),
+        "The TLS block must be writable"
+    );
+
+    Some(Block {
+        start,
+        length,
+        thread_pointer: thread_pointer as u64,
+    })
+}
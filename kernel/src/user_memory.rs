@@ -0,0 +1,69 @@
+use alloc::{vec, vec::Vec};
+
+use crate::{syscall::SyscallError, Memory, PROCESSES, USER_END};
+
+/// Copies `buffer.len()` bytes from `address` in the running process. Fails
+/// with `EFAULT` unless the whole range is in the user half and readable by
+/// the process. Must not be called with `PROCESSES` locked.
+pub fn copy_from_user(address: u64, buffer: &mut [u8]) -> Result<(), SyscallError> {
+    let read = Memory::current(&mut PROCESSES.lock())
+        .address_space
+        .read(address as usize, buffer);
+
+    read.then_some(()).ok_or(SyscallError::EFAULT)
+}
+
+/// Copies `bytes` to `address` in the running process. Fails with `EFAULT`
+/// unless the whole range is in the user half and writable by the process.
+/// Must not be called with `PROCESSES` locked.
+pub fn copy_to_user(address: u64, bytes: &[u8]) -> Result<(), SyscallError> {
+    let written = Memory::current(&mut PROCESSES.lock())
+        .address_space
+        .write(address as usize, bytes);
+
+    written.then_some(()).ok_or(SyscallError::EFAULT)
+}
+
+/// A buffer in the memory of the running process, as passed to a system
+/// call.
+#[derive(Debug, Clone, Copy)]
+pub struct UserSlice {
+    address: u64,
+    length: usize,
+}
+
+impl UserSlice {
+    /// Fails with `EFAULT` if the range does not lie in the user half.
+    /// Whether it is mapped is only checked when it is accessed.
+    pub fn new(address: u64, length: u64) -> Result<Self, SyscallError> {
+        if address
+            .checked_add(length)
+            .is_none_or(|end| end > USER_END as u64)
+        {
+            return Err(SyscallError::EFAULT);
+        }
+
+        Ok(UserSlice {
+            address,
+            length: length as usize,
+        })
+    }
+
+    /// Copies the contents of the buffer into the kernel.
+    pub fn read(&self) -> Result<Vec<u8>, SyscallError> {
+        let mut buffer = vec![0; self.length];
+        copy_from_user(self.address, &mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    /// Copies `bytes` to the start of the buffer, failing with `EINVAL` if
+    /// they do not fit.
+    pub fn write(&self, bytes: &[u8]) -> Result<(), SyscallError> {
+        if bytes.len() > self.length {
+            return Err(SyscallError::EINVAL);
+        }
+
+        copy_to_user(self.address, bytes)
+    }
+}
@@ -0,0 +1,79 @@
+use alloc::vec::Vec;
+
+use crate::{address_space::AddressSpace, random};
+
+// Auxiliary vector entry types from the System V ABI.
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
+/// Writes the initial stack of a process below `top`, laid out as the
+/// System V ABI expects: the argument count, the argument and environment
+/// pointer arrays and the auxiliary vector, followed by the strings they
+/// point to. `AT_PAGESZ` and `AT_RANDOM` are added to `auxiliary`. Returns
+/// the stack pointer to start the process with.
+pub fn build(
+    address_space: &mut AddressSpace,
+    top: usize,
+    arguments: &[&str],
+    environment: &[&str],
+    auxiliary: &[(u64, u64)],
+) -> usize {
+    // Sixteen random bytes at the very top, for `AT_RANDOM`.
+    let random_bytes = top - 16;
+    let mut random = [0; 16];
+    random[..8].copy_from_slice(&random::random_u64().to_le_bytes());
+    random[8..].copy_from_slice(&random::random_u64().to_le_bytes());
+
+    // The strings go right below, each ending in a null byte.
+    let length: usize = arguments
+        .iter()
+        .chain(environment)
+        .map(|string| string.len() + 1)
+        .sum();
+    let strings_start = random_bytes - length;
+
+    let mut strings = Vec::with_capacity(length);
+    let mut pointers = Vec::with_capacity(arguments.len() + environment.len());
+    for string in arguments.iter().chain(environment) {
+        pointers.push((strings_start + strings.len()) as u64);
+        strings.extend_from_slice(string.as_bytes());
+        strings.push(0);
+    }
+
+    let (argv, envp) = pointers.split_at(arguments.len());
+
+    let mut words = Vec::new();
+    words.push(arguments.len() as u64);
+    words.extend_from_slice(argv);
+    words.push(0);
+    words.extend_from_slice(envp);
+    words.push(0);
+
+    for &(key, value) in auxiliary {
+        words.extend_from_slice(&[key, value]);
+    }
+    words.extend_from_slice(&[AT_PAGESZ, 4096, AT_RANDOM, random_bytes as u64, AT_NULL, 0]);
+
+    // The stack pointer must be 16-byte aligned on entry.
+    let sp = (strings_start - words.len() * 8) & !0xf;
+
+    let words: Vec<u8> = words
+        .iter()
+        .flat_map(|word: &u64| word.to_le_bytes())
+        .collect();
+
+    assert!(
+        address_space.write(random_bytes, &random)
+            && address_space.write(strings_start, &strings)
+            && address_space.write(sp, &words),
+        "The initial stack must be writable"
+    );
+
+    sp
+}
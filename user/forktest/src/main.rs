@@ -0,0 +1,56 @@
+//! Checks that a forked child and its parent each get their own copy of
+//! every kind of writable memory they shared at the fork.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec;
+use core::hint::black_box;
+use runtime::{println, syscall};
+
+runtime::entry!(main);
+
+static mut DATA: u64 = 1;
+static mut BSS: u64 = 0;
+
+/// Writes `value` to the data and bss segments, the heap and the stack, and
+/// checks that each still holds it afterwards.
+fn write_and_check(heap: &mut [u64], value: u64) -> bool {
+    let mut stack = black_box([0u64; 512]);
+
+    unsafe {
+        DATA = value;
+        BSS = value;
+    }
+    heap.fill(value);
+    stack.fill(value);
+
+    // Let the other side of the fork run and write its own values.
+    for _ in 0..10_000_000 {
+        black_box(());
+    }
+
+    let stack = black_box(stack);
+    let statics = unsafe { DATA == value && BSS == value };
+
+    statics && heap.iter().all(|&word| word == value) && stack.iter().all(|&word| word == value)
+}
+
+fn main() -> i32 {
+    let mut heap = vec![0u64; 1024];
+
+    let (name, value) = match syscall::fork() {
+        0 => ("child", 2),
+        _ => ("parent", 3),
+    };
+
+    if !write_and_check(&mut heap, value) {
+        println!("Fork test failed in the {}", name);
+        return 1;
+    }
+
+    println!("Fork test passed in the {}", name);
+    0
+}
@@ -0,0 +1,34 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use sharkos as _;
+
+fn main() {
+    let start = Instant::now();
+    let arguments: Vec<String> = std::env::args().collect();
+    println!("Hello from std, started as {:?}", arguments);
+
+    let mut counts = HashMap::new();
+    for word in "the quick brown fox jumps over the lazy dog".split(' ') {
+        *counts.entry(word).or_insert(0) += 1;
+    }
+    println!("\"the\" appears {} times", counts["the"]);
+
+    let total = Arc::new(Mutex::new(0));
+    let threads: Vec<_> = (1..=4)
+        .map(|number| {
+            let total = Arc::clone(&total);
+            thread::spawn(move || *total.lock().unwrap() += number)
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    println!("Threads added up to {}", total.lock().unwrap());
+
+    println!("Done in {:?}", start.elapsed());
+}
@@ -0,0 +1,20 @@
+#![no_std]
+#![no_main]
+
+use runtime::{println, syscall};
+
+runtime::entry!(main);
+
+fn main() -> i32 {
+    for path in ["/bin/program2", "/bin/hello", "/bin/forktest"] {
+        if syscall::spawn(path).is_err() {
+            println!("Failed to spawn {}", path);
+            return 1;
+        }
+    }
+
+    loop {
+        println!("from process 1");
+        for _ in 0..10_000_000 {}
+    }
+}
@@ -0,0 +1,19 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use runtime::println;
+
+runtime::entry!(main);
+
+fn main() -> i32 {
+    let mut counts = Vec::new();
+
+    loop {
+        counts.push(counts.len());
+        println!("from process 2, {} loops", counts.len());
+        for _ in 0..10_000_000 {}
+    }
+}
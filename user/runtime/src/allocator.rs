@@ -0,0 +1,130 @@
+//! The global allocator. Small blocks come from free lists of power-of-two
+//! sizes, refilled by moving the program break, while large ones get pages
+//! of their own from `mmap`.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+};
+
+use crate::{
+    lock::Lock,
+    syscall::{self, PROT_READ, PROT_WRITE},
+};
+
+const PAGE_SIZE: usize = 4096;
+
+/// The smallest block, which can hold a free list link.
+const MIN_BLOCK: usize = 16;
+/// The largest block handed out from the free lists.
+const MAX_BLOCK: usize = 2048;
+const CLASSES: usize = (MAX_BLOCK / MIN_BLOCK).trailing_zeros() as usize + 1;
+
+/// How far the program break is moved at a time.
+const GROWTH: usize = 16 * PAGE_SIZE;
+
+struct Heap {
+    /// The first free block of each size, each holding a pointer to the next.
+    free: [*mut u8; CLASSES],
+    /// The part of the heap that was never handed out.
+    next: usize,
+    end: usize,
+}
+
+unsafe impl Send for Heap {}
+
+pub struct Allocator {
+    heap: Lock<Heap>,
+}
+
+#[cfg_attr(feature = "start", global_allocator)]
+pub static ALLOCATOR: Allocator = Allocator {
+    heap: Lock::new(Heap {
+        free: [ptr::null_mut(); CLASSES],
+        next: 0,
+        end: 0,
+    }),
+};
+
+/// The size of the block for `layout`, or `None` if it is too large for the
+/// free lists. Blocks are aligned to their size.
+fn block_size(layout: Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(MIN_BLOCK)
+        .next_power_of_two();
+    (size <= MAX_BLOCK).then_some(size)
+}
+
+fn class(size: usize) -> usize {
+    (size / MIN_BLOCK).trailing_zeros() as usize
+}
+
+impl Heap {
+    /// Carves a block of `size` bytes out of the unused part of the heap,
+    /// moving the program break if needed.
+    fn carve(&mut self, size: usize) -> *mut u8 {
+        if self.end == 0 {
+            self.next = syscall::brk(0);
+            self.end = self.next;
+        }
+
+        let start = self.next.next_multiple_of(size);
+
+        if start + size > self.end {
+            let end = syscall::brk((start + size).max(self.end + GROWTH));
+            if end < start + size {
+                return ptr::null_mut();
+            }
+
+            self.end = end;
+        }
+
+        self.next = start + size;
+        start as *mut u8
+    }
+
+    fn allocate(&mut self, size: usize) -> *mut u8 {
+        let free = &mut self.free[class(size)];
+
+        if free.is_null() {
+            return self.carve(size);
+        }
+
+        let block = *free;
+        *free = unsafe { *(block as *mut *mut u8) };
+        block
+    }
+
+    fn free(&mut self, block: *mut u8, size: usize) {
+        let free = &mut self.free[class(size)];
+
+        unsafe { *(block as *mut *mut u8) = *free };
+        *free = block;
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(size) = block_size(layout) {
+            return self.heap.with(|heap| heap.allocate(size));
+        }
+
+        // Mappings are only page aligned.
+        if layout.align() > PAGE_SIZE {
+            return ptr::null_mut();
+        }
+
+        syscall::mmap(0, layout.size(), PROT_READ | PROT_WRITE).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        match block_size(layout) {
+            Some(size) => self.heap.with(|heap| heap.free(block, size)),
+            None => {
+                syscall::munmap(block, layout.size()).expect("Failed to unmap an allocation");
+            }
+        }
+    }
+}
@@ -0,0 +1,17 @@
+//! The entry point of every program, which hands the initial stack to
+//! `__runtime_start`. That is defined by the `start` feature for programs
+//! without std, and by `sharkos` for programs with it.
+
+use core::arch::global_asm;
+
+// The kernel starts programs with the stack pointer on the argument count,
+// followed by the argument and environment pointers.
+global_asm!(
+    ".global _start",
+    "_start:",
+    "mov rdi, rsp",
+    "xor ebp, ebp",
+    "and rsp, -16",
+    "call __runtime_start",
+    "ud2",
+);
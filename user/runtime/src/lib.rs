@@ -0,0 +1,70 @@
+//! The runtime of user programs: the entry point, printing, a panic handler
+//! and a global allocator, on top of the kernel's system calls. Without the
+//! default `start` feature, as used under `std`, there is no panic handler
+//! or global allocator, and `sharkos` takes over from the entry point.
+//!
+//! A program names its main function with [`entry!`]:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! runtime::entry!(main);
+//!
+//! fn main() -> i32 {
+//!     runtime::println!("Hello!");
+//!     0
+//! }
+//! ```
+
+#![no_std]
+
+pub mod allocator;
+mod entry;
+pub mod lock;
+#[cfg(feature = "start")]
+mod start;
+pub mod syscall;
+
+#[cfg(feature = "start")]
+pub use start::args;
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::_print(format_args!($($arg)*)));
+}
+
+/// Makes `$main`, a `fn() -> i32`, the function the program starts in. Its
+/// return value is the exit code of the process.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[export_name = "__runtime_main"]
+        fn __runtime_main() -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
+
+struct SystemWriter;
+
+impl core::fmt::Write for SystemWriter {
+    fn write_str(&mut self, message: &str) -> core::fmt::Result {
+        syscall::write(message);
+
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    SystemWriter.write_fmt(args).ok();
+}
@@ -0,0 +1,42 @@
+//! The start and panic handler of programs without std.
+
+use core::{
+    ffi::{c_char, CStr},
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use crate::{println, syscall};
+
+/// The exit code of a process that panicked.
+const PANIC_EXIT_CODE: i32 = 101;
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    println!("{}", info);
+    syscall::exit(PANIC_EXIT_CODE);
+}
+
+static ARGUMENT_COUNT: AtomicUsize = AtomicUsize::new(0);
+static ARGUMENTS: AtomicPtr<*const c_char> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Returns the arguments the program was started with.
+pub fn args() -> impl ExactSizeIterator<Item = &'static str> {
+    let arguments = ARGUMENTS.load(Ordering::Relaxed);
+
+    (0..ARGUMENT_COUNT.load(Ordering::Relaxed)).map(move |index| {
+        let argument = unsafe { CStr::from_ptr(*arguments.add(index)) };
+        argument.to_str().unwrap_or("")
+    })
+}
+
+extern "Rust" {
+    fn __runtime_main() -> i32;
+}
+
+#[no_mangle]
+unsafe extern "C" fn __runtime_start(stack: *const usize) -> ! {
+    ARGUMENT_COUNT.store(*stack, Ordering::Relaxed);
+    ARGUMENTS.store(stack.add(1) as *mut *const c_char, Ordering::Relaxed);
+
+    syscall::exit(__runtime_main())
+}
@@ -0,0 +1,240 @@
+//! Typed wrappers around the kernel's system calls.
+
+use core::{arch::asm, sync::atomic::AtomicU32};
+
+/// The largest error number. System calls fail by returning one negated.
+const MAX_ERROR: isize = 4095;
+
+pub const PROT_READ: usize = 1;
+pub const PROT_WRITE: usize = 2;
+pub const PROT_EXEC: usize = 4;
+
+/// How physical memory is used, in pages, as reported by `meminfo`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryStatistics {
+    pub total: u64,
+    pub free: u64,
+    pub reserved: u64,
+    pub kernel_heap: u64,
+    pub page_tables: u64,
+    pub user: u64,
+}
+
+/// The error number returned by a failed system call, as on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error(pub i32);
+
+pub const EPERM: Error = Error(1);
+pub const ENOENT: Error = Error(2);
+pub const ESRCH: Error = Error(3);
+pub const ENOEXEC: Error = Error(8);
+pub const EAGAIN: Error = Error(11);
+pub const ENOMEM: Error = Error(12);
+pub const EFAULT: Error = Error(14);
+pub const EINVAL: Error = Error(22);
+pub const ENOSYS: Error = Error(38);
+
+type Result<T> = core::result::Result<T, Error>;
+
+unsafe fn syscall1(code: usize, r1: usize) -> usize {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") code => result,
+        in("rdi") r1,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    result
+}
+
+unsafe fn syscall2(code: usize, r1: usize, r2: usize) -> usize {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") code => result,
+        in("rdi") r1,
+        in("rsi") r2,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    result
+}
+
+unsafe fn syscall3(code: usize, r1: usize, r2: usize, r3: usize) -> usize {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") code => result,
+        in("rdi") r1,
+        in("rsi") r2,
+        in("rdx") r3,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    result
+}
+
+unsafe fn syscall4(code: usize, r1: usize, r2: usize, r3: usize, r4: usize) -> usize {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") code => result,
+        in("rdi") r1,
+        in("rsi") r2,
+        in("rdx") r3,
+        in("r10") r4,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    result
+}
+
+fn check(result: usize) -> Result<usize> {
+    match (result as isize).checked_neg() {
+        Some(error @ 1..=MAX_ERROR) => Err(Error(error as i32)),
+        _ => Ok(result),
+    }
+}
+
+pub fn exit(code: i32) -> ! {
+    unsafe {
+        asm!(
+            "syscall",
+            in("rax") 0usize,
+            in("rdi") code as usize,
+            options(noreturn, nostack)
+        )
+    }
+}
+
+/// Prints `message` to the console. The kernel prints long messages a part
+/// at a time.
+pub fn write(message: &str) {
+    let mut remaining = message.as_bytes();
+
+    while !remaining.is_empty() {
+        match check(unsafe { syscall2(1, remaining.as_ptr() as usize, remaining.len()) }) {
+            Ok(written) if written > 0 => remaining = &remaining[written..],
+            _ => break,
+        }
+    }
+}
+
+pub fn meminfo() -> MemoryStatistics {
+    let mut statistics = MemoryStatistics::default();
+    unsafe { syscall1(2, &mut statistics as *mut _ as usize) };
+    statistics
+}
+
+/// Duplicates the process. Returns zero in the child and the child's pid in
+/// the parent.
+pub fn fork() -> usize {
+    unsafe { syscall1(3, 0) }
+}
+
+/// Maps `length` bytes of zeroed memory, at `address` if that is free and
+/// anywhere otherwise.
+pub fn mmap(address: usize, length: usize, protection: usize) -> Result<*mut u8> {
+    check(unsafe { syscall3(4, address, length, protection) }).map(|address| address as *mut u8)
+}
+
+pub fn munmap(address: *mut u8, length: usize) -> Result<()> {
+    check(unsafe { syscall2(5, address as usize, length) }).map(drop)
+}
+
+pub fn mprotect(address: *mut u8, length: usize, protection: usize) -> Result<()> {
+    check(unsafe { syscall3(6, address as usize, length, protection) }).map(drop)
+}
+
+/// Moves the program break to `address`, and returns where it ends up. The
+/// break is left where it was if it cannot be moved.
+pub fn brk(address: usize) -> usize {
+    unsafe { syscall1(7, address) }
+}
+
+/// Creates a shared memory object of `size` bytes and maps it.
+pub fn shm_create(size: usize, protection: usize) -> Result<*mut u8> {
+    check(unsafe { syscall2(8, size, protection) }).map(|address| address as *mut u8)
+}
+
+/// Allows process `pid` to map the shared memory object mapped at `address`.
+/// Returns the id of the object.
+pub fn shm_grant(address: *mut u8, pid: usize) -> Result<usize> {
+    check(unsafe { syscall2(9, address as usize, pid) })
+}
+
+pub fn shm_map(id: usize, protection: usize) -> Result<*mut u8> {
+    check(unsafe { syscall2(10, id, protection) }).map(|address| address as *mut u8)
+}
+
+pub fn shm_unmap(address: *mut u8) -> Result<()> {
+    check(unsafe { syscall1(11, address as usize) }).map(drop)
+}
+
+/// Starts the program at `path` in the ramdisk. Returns its pid.
+pub fn spawn(path: &str) -> Result<usize> {
+    check(unsafe { syscall2(12, path.as_ptr() as usize, path.len()) })
+}
+
+/// Nanoseconds since boot.
+pub fn time() -> u64 {
+    unsafe { syscall1(13, 0) as u64 }
+}
+
+/// Starts a thread in this process that calls `entry(argument)` with the
+/// stack pointer at `stack`, which should be 8 below a 16-byte boundary as
+/// if `entry` had been called. When the thread exits, the kernel zeroes the
+/// word at `exit_address`, if it is not null, and wakes it as a futex.
+/// Returns the pid of the thread.
+///
+/// # Safety
+///
+/// The stack must stay mapped for as long as the thread runs, and
+/// `exit_address` must stay valid until the thread has exited.
+pub unsafe fn spawn_thread(
+    entry: extern "C" fn(usize) -> !,
+    stack: *mut u8,
+    argument: usize,
+    exit_address: *const AtomicU32,
+) -> Result<usize> {
+    check(syscall4(
+        14,
+        entry as usize,
+        stack as usize,
+        argument,
+        exit_address as usize,
+    ))
+}
+
+/// Ends the calling thread. Ending the first thread ends the process.
+pub fn exit_thread() -> ! {
+    unsafe {
+        asm!(
+            "syscall",
+            in("rax") 15usize,
+            options(noreturn, nostack)
+        )
+    }
+}
+
+/// Sleeps until another thread wakes `futex`, unless it no longer holds
+/// `expected`, in which case it fails with `EAGAIN` straight away.
+pub fn futex_wait(futex: &AtomicU32, expected: u32) -> Result<()> {
+    check(unsafe { syscall2(16, futex.as_ptr() as usize, expected as usize) }).map(drop)
+}
+
+/// Wakes up to `count` threads waiting on `futex`. Returns how many woke.
+pub fn futex_wake(futex: &AtomicU32, count: usize) -> usize {
+    unsafe { syscall2(17, futex.as_ptr() as usize, count) }
+}
+
+/// Lets other threads run.
+pub fn yield_now() {
+    unsafe { syscall1(18, 0) };
+}
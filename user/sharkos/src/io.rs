@@ -0,0 +1,88 @@
+//! Standard output and error. There is no input yet, and no files.
+
+use core::ffi::c_char;
+
+use runtime::syscall;
+
+use crate::{fail, EBADF, ENOSYS};
+
+#[repr(C)]
+pub struct IoVec {
+    base: *const u8,
+    length: usize,
+}
+
+/// Writes as much of `bytes` to the console as is valid UTF-8, replacing an
+/// invalid sequence at the start. Returns how many bytes were consumed.
+fn write_console(bytes: &[u8]) -> usize {
+    match core::str::from_utf8(bytes) {
+        Ok(message) => {
+            syscall::write(message);
+            bytes.len()
+        }
+        Err(error) if error.valid_up_to() > 0 => {
+            let valid = &bytes[..error.valid_up_to()];
+            syscall::write(unsafe { core::str::from_utf8_unchecked(valid) });
+            valid.len()
+        }
+        Err(error) => {
+            syscall::write("\u{fffd}");
+            // A truncated sequence at the end is consumed whole.
+            error.error_len().unwrap_or(bytes.len())
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sys_write(fd: i32, buffer: *const u8, length: usize) -> isize {
+    if !matches!(fd, 1 | 2) {
+        return fail(EBADF) as isize;
+    }
+
+    if length == 0 {
+        return 0;
+    }
+
+    write_console(core::slice::from_raw_parts(buffer, length)) as isize
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sys_writev(fd: i32, vectors: *const IoVec, count: usize) -> isize {
+    let mut written = 0;
+
+    for vector in core::slice::from_raw_parts(vectors, count) {
+        let result = sys_write(fd, vector.base, vector.length);
+        if result < 0 {
+            return result;
+        }
+
+        written += result;
+        if result as usize != vector.length {
+            break;
+        }
+    }
+
+    written
+}
+
+/// Standard input is always at its end.
+#[no_mangle]
+pub extern "C" fn sys_read(fd: i32, _buffer: *mut u8, _length: usize) -> isize {
+    match fd {
+        0 => 0,
+        _ => fail(EBADF) as isize,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_open(_name: *const c_char, _flags: i32, _mode: i32) -> i32 {
+    fail(ENOSYS)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_close(fd: i32) -> i32 {
+    match fd {
+        0..=2 => 0,
+        _ => fail(EBADF),
+    }
+}
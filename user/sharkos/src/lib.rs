@@ -0,0 +1,72 @@
+//! The platform layer that lets user programs use `std`. Programs are built
+//! for the `x86_64-sharkos` target, which uses the standard library's Hermit
+//! support. Hermit's `std` calls into the kernel through the `sys_*`
+//! functions of the `hermit-abi` crate, which this crate implements on top
+//! of our system calls. Programs link it in with `use sharkos as _;`.
+//!
+//! The target spec, `user/x86_64-sharkos.json`, leaves SSE off like
+//! `x86_64-unknown-none`, as the kernel does not save vector registers when
+//! switching processes.
+
+#![no_std]
+
+extern crate alloc;
+
+use core::{
+    ffi::c_char,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use runtime::syscall;
+
+mod io;
+mod memory;
+mod thread;
+mod time;
+
+// Error numbers, as used by Hermit.
+const EBADF: i32 = 9;
+const EAGAIN: i32 = 11;
+const EINVAL: i32 = 22;
+const ENOSYS: i32 = 38;
+const ETIMEDOUT: i32 = 110;
+
+/// The error number of the last call that failed.
+static ERRNO: AtomicI32 = AtomicI32::new(0);
+
+/// Records `errno` and returns it negated, the way failures are reported.
+fn fail(errno: i32) -> i32 {
+    ERRNO.store(errno, Ordering::Relaxed);
+    -errno
+}
+
+#[no_mangle]
+pub extern "C" fn sys_get_errno() -> i32 {
+    ERRNO.load(Ordering::Relaxed)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_exit(status: i32) -> ! {
+    syscall::exit(status)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_abort() -> ! {
+    syscall::exit(134)
+}
+
+extern "C" {
+    /// Defined by `std`, which initializes itself and calls `main`.
+    fn runtime_entry(argc: i32, argv: *const *const c_char, env: *const *const c_char) -> !;
+}
+
+/// Called by the entry point in `runtime` with the stack the kernel started
+/// the program on.
+#[no_mangle]
+unsafe extern "C" fn __runtime_start(stack: *const usize) -> ! {
+    let argc = *stack;
+    let argv = stack.add(1) as *const *const c_char;
+    let env = argv.add(argc + 1);
+
+    runtime_entry(argc as i32, argv, env)
+}
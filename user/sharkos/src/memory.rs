@@ -0,0 +1,54 @@
+//! The heap, backed by the runtime's allocator.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+};
+
+use runtime::allocator::ALLOCATOR;
+
+#[no_mangle]
+pub unsafe extern "C" fn sys_alloc(size: usize, align: usize) -> *mut u8 {
+    match Layout::from_size_align(size, align) {
+        Ok(layout) => ALLOCATOR.alloc(layout),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sys_alloc_zeroed(size: usize, align: usize) -> *mut u8 {
+    match Layout::from_size_align(size, align) {
+        Ok(layout) => ALLOCATOR.alloc_zeroed(layout),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sys_realloc(
+    block: *mut u8,
+    size: usize,
+    align: usize,
+    new_size: usize,
+) -> *mut u8 {
+    match Layout::from_size_align(size, align) {
+        Ok(layout) => ALLOCATOR.realloc(block, layout, new_size),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sys_dealloc(block: *mut u8, size: usize, align: usize) {
+    ALLOCATOR.dealloc(block, Layout::from_size_align_unchecked(size, align));
+}
+
+// The names used by older versions of `hermit-abi`.
+
+#[no_mangle]
+pub unsafe extern "C" fn sys_malloc(size: usize, align: usize) -> *mut u8 {
+    sys_alloc(size, align)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sys_free(block: *mut u8, size: usize, align: usize) {
+    sys_dealloc(block, size, align)
+}
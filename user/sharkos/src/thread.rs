@@ -0,0 +1,202 @@
+//! Threads and the futexes that `std` synchronizes them with.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use runtime::{
+    lock::Lock,
+    syscall::{self, PROT_READ, PROT_WRITE},
+};
+
+use crate::{fail, time, EAGAIN, EINVAL, ETIMEDOUT};
+
+type Tid = i32;
+
+/// Makes a futex timeout relative to now rather than to boot.
+const FUTEX_RELATIVE_TIMEOUT: u32 = 1;
+
+const PAGE_SIZE: usize = 4096;
+
+/// The stack size of threads started by `sys_spawn`, which does not ask
+/// for one. Stacks are backed as they are used.
+const DEFAULT_STACK_SIZE: usize = 2 * 1024 * 1024;
+
+/// A thread started by `sys_spawn2`, kept until it is joined.
+struct Thread {
+    function: extern "C" fn(usize),
+    argument: usize,
+    /// One until the thread exits, when the kernel zeroes it and wakes it
+    /// as a futex.
+    running: AtomicU32,
+    /// The mapping the stack is in, which starts with a guard page.
+    stack: *mut u8,
+    stack_length: usize,
+}
+
+unsafe impl Send for Thread {}
+
+/// The threads that have not been joined yet, by tid.
+static THREADS: Lock<Vec<(Tid, Box<Thread>)>> = Lock::new(Vec::new());
+
+extern "C" fn thread_start(thread: usize) -> ! {
+    let thread = unsafe { &*(thread as *const Thread) };
+
+    (thread.function)(thread.argument);
+    syscall::exit_thread()
+}
+
+/// Starts a thread running `function(argument)`. Returns its tid, which is
+/// never zero, or zero if it could not be started.
+#[no_mangle]
+pub extern "C" fn sys_spawn2(
+    function: extern "C" fn(usize),
+    argument: usize,
+    _priority: u8,
+    stack_size: usize,
+    _core: isize,
+) -> Tid {
+    let stack_length = stack_size.max(PAGE_SIZE).next_multiple_of(PAGE_SIZE) + PAGE_SIZE;
+    let Ok(stack) = syscall::mmap(0, stack_length, PROT_READ | PROT_WRITE) else {
+        return 0;
+    };
+
+    let thread = Box::new(Thread {
+        function,
+        argument,
+        running: AtomicU32::new(1),
+        stack,
+        stack_length,
+    });
+
+    let started = syscall::mprotect(stack, PAGE_SIZE, 0).and_then(|()| unsafe {
+        // Leave the stack as if `thread_start` had been called.
+        let top = stack.add(stack_length - 8);
+
+        syscall::spawn_thread(
+            thread_start,
+            top,
+            &*thread as *const Thread as usize,
+            &thread.running,
+        )
+    });
+
+    match started {
+        // Pids start at zero, which would read as a failure.
+        Ok(pid) => {
+            let tid = pid as Tid + 1;
+            THREADS.with(|threads| threads.push((tid, thread)));
+            tid
+        }
+        Err(_) => {
+            syscall::munmap(stack, stack_length).ok();
+            0
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sys_spawn(
+    id: *mut Tid,
+    function: extern "C" fn(usize),
+    argument: usize,
+    priority: u8,
+    core: isize,
+) -> i32 {
+    match sys_spawn2(function, argument, priority, DEFAULT_STACK_SIZE, core) {
+        0 => fail(EAGAIN),
+        tid => {
+            if !id.is_null() {
+                id.write(tid);
+            }
+
+            0
+        }
+    }
+}
+
+/// Waits for a thread to exit and frees its stack.
+#[no_mangle]
+pub extern "C" fn sys_join(id: Tid) -> i32 {
+    let thread = THREADS.with(|threads| {
+        let index = threads.iter().position(|&(tid, _)| tid == id)?;
+        Some(threads.swap_remove(index).1)
+    });
+
+    let Some(thread) = thread else {
+        return fail(EINVAL);
+    };
+
+    loop {
+        match thread.running.load(Ordering::Acquire) {
+            0 => break,
+            value => syscall::futex_wait(&thread.running, value).ok(),
+        };
+    }
+
+    syscall::munmap(thread.stack, thread.stack_length).expect("Failed to unmap a thread stack");
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn sys_yield() {
+    syscall::yield_now();
+}
+
+#[no_mangle]
+pub extern "C" fn sys_getpid() -> u32 {
+    // There is no system call for it yet.
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn sys_available_parallelism() -> usize {
+    1
+}
+
+#[no_mangle]
+pub extern "C" fn sys_get_processor_count() -> usize {
+    1
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sys_futex_wait(
+    address: *mut u32,
+    expected: u32,
+    timeout: *const time::Timespec,
+    flags: u32,
+) -> i32 {
+    let futex = AtomicU32::from_ptr(address);
+
+    let Some(timeout) = timeout.as_ref() else {
+        return match syscall::futex_wait(futex, expected) {
+            Ok(()) => 0,
+            Err(_) => fail(EAGAIN),
+        };
+    };
+
+    let mut deadline = timeout.to_nanoseconds();
+    if flags & FUTEX_RELATIVE_TIMEOUT != 0 {
+        deadline = deadline.saturating_add(syscall::time());
+    }
+
+    if futex.load(Ordering::Acquire) != expected {
+        return fail(EAGAIN);
+    }
+
+    // The kernel cannot time out a wait, so check back between the turns of
+    // the other threads. A change of value stands in for a wake up.
+    while futex.load(Ordering::Acquire) == expected {
+        if syscall::time() >= deadline {
+            return fail(ETIMEDOUT);
+        }
+
+        syscall::yield_now();
+    }
+
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sys_futex_wake(address: *mut u32, count: i32) -> i32 {
+    syscall::futex_wake(AtomicU32::from_ptr(address), count.max(0) as usize) as i32
+}
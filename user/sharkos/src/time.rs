@@ -0,0 +1,51 @@
+//! Clocks and sleeping. There is no real-time clock yet, so both clocks
+//! count from boot.
+
+use runtime::syscall;
+
+use crate::{fail, EINVAL};
+
+/// A `timespec`. Versions of `hermit-abi` disagree on whether the
+/// nanoseconds are 32 or 64 bits wide, so they are written as 64 bits and
+/// read from the low 32.
+#[repr(C)]
+pub struct Timespec {
+    seconds: i64,
+    nanoseconds: i64,
+}
+
+impl Timespec {
+    pub fn to_nanoseconds(&self) -> u64 {
+        (self.seconds as u64)
+            .saturating_mul(1_000_000_000)
+            .saturating_add(self.nanoseconds as u32 as u64)
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sys_clock_gettime(_clock: u64, time: *mut Timespec) -> i32 {
+    if time.is_null() {
+        return fail(EINVAL);
+    }
+
+    let now = syscall::time();
+    time.write(Timespec {
+        seconds: (now / 1_000_000_000) as i64,
+        nanoseconds: (now % 1_000_000_000) as i64,
+    });
+
+    0
+}
+
+/// Waits until the clock passes `deadline`, in nanoseconds since boot. There
+/// is no system call to sleep with yet, so other threads run in the meantime.
+pub fn wait_until(deadline: u64) {
+    while syscall::time() < deadline {
+        syscall::yield_now();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_usleep(microseconds: u64) {
+    wait_until(syscall::time().saturating_add(microseconds.saturating_mul(1000)));
+}